@@ -2,6 +2,10 @@
 
 use crate::sbi::console_putchar;
 use core::fmt::{self, Write};
+use spin::Mutex;
+
+/// 避免多个核的输出交错在一起
+static PRINT_LOCK: Mutex<()> = Mutex::new(());
 
 struct Stdout;
 
@@ -15,9 +19,18 @@ impl Write for Stdout {
 }
 
 pub fn print(args: fmt::Arguments) {
+    let _guard = PRINT_LOCK.lock();
     Stdout.write_fmt(args).unwrap();
 }
 
+/// 输出 guest 的一行内容并添加 `[guest N]` 前缀
+pub fn print_guest_line(guest_id: usize, line: &[u8]) {
+    let _guard = PRINT_LOCK.lock();
+    Stdout.write_fmt(format_args!("[guest {}] ", guest_id)).unwrap();
+    line.iter().for_each(|&c| console_putchar(c as usize));
+    console_putchar(b'\n' as usize);
+}
+
 #[macro_export]
 /// print string macro
 macro_rules! print {
@@ -106,6 +106,7 @@ pub const sideleg: usize = 0x103;
 pub const sie: usize = 0x104;
 pub const stvec: usize = 0x105;
 pub const scounteren: usize = 0x106;
+pub const senvcfg: usize = 0x10a;
 pub const stvt: usize = 0x107;
 pub const sscratch: usize = 0x140;
 pub const sepc: usize = 0x141;
@@ -116,6 +117,7 @@ pub const sip: usize = 0x144;
 pub const snxti: usize = 0x145;
 pub const sintstatus: usize = 0x146;
 pub const sscratchcsw: usize = 0x148;
+pub const stimecmp: usize = 0x14d;
 pub const sptbr: usize = 0x180;
 pub const satp: usize = 0x180;
 pub const pmpcfg0: usize = 0x3a0;
@@ -274,11 +276,35 @@ pub mod status {
     pub const STATUS_XS: usize = 3 << 15;
     pub const STATUS_SUM: usize = 1 << 18;
     pub const STATUS_MXR: usize = 1 << 19;
+    pub const STATUS_UXL: usize = 3 << 32;
     pub const STATUS_SD: usize = 1 << 63;
+    /// `sstatus` 中 guest 可以写入的位，其余位只读
+    pub const SSTATUS_WRITABLE: usize = STATUS_SIE | STATUS_SPIE | STATUS_SPP | STATUS_FS | STATUS_SUM | STATUS_MXR;
+    /// RV64 中 `UXL` 固定为 64 位
+    pub const STATUS_UXL_64: usize = 2 << 32;
+
+    /// `FS` 字段的取值
+    pub const FS_OFF: usize = 0;
+    pub const FS_INITIAL: usize = 1 << 13;
+    pub const FS_CLEAN: usize = 2 << 13;
+    pub const FS_DIRTY: usize = 3 << 13;
 
     pub const STATUS_SIE_BIT: usize = 1;
 
     pub const STATUS_SPIE_BIT: usize = 5;
 
     pub const STATUS_SPP_BIT: usize = 8;
-}
\ No newline at end of file
+}
+
+pub mod counteren {
+    pub const CY: usize = 1 << 0;
+    pub const TM: usize = 1 << 1;
+    pub const IR: usize = 1 << 2;
+    /// `scounteren` 为 32 位寄存器
+    pub const COUNTEREN_MASK: usize = 0xffff_ffff;
+}
+
+pub mod envcfg {
+    /// Fence of I/O implies Memory
+    pub const FIOM: usize = 1 << 0;
+}
@@ -28,6 +28,27 @@ pub const GUEST_KERNEL_VIRT_START_3: usize = 0x8000_0000;
 
 pub const GUEST_KERNEL_OFFSET_1: usize = 0x800_0000;
 
+/// 最多支持的物理核数量
+pub const MAX_HARTS: usize = 8;
+
+/// 最多同时存在的 guest kernel 数量
+pub const MAX_GUESTS: usize = 3;
+
+/// 每个 guest kernel 最多拥有的 vCPU 数量
+pub const MAX_VCPUS: usize = 4;
+
+/// Return (start, end) of guest kernel physical memory segment.
+pub fn guest_kernel_phy_position(guest_id: usize) -> (usize, usize) {
+    let start = GUEST_KERNEL_PHY_START_1 + guest_id * KERNEL_SPACE;
+    (start, start + KERNEL_SPACE)
+}
+
+/// Return (start, end) of guest kernel shadow page table region.
+pub fn spt_position(guest_id: usize) -> (usize, usize) {
+    let start = SPT_PA_START_1 + guest_id * KERNEL_SPACE;
+    (start, start + KERNEL_SPACE)
+}
+
 /// 测试内核的跳板页和 Trap Context 的地址
 pub const GUEST_MAX_VA: usize = 1 << (9 + 9 + 9 + 12 - 1);
 pub const GUEST_TRAMPOLINE: usize = GUEST_MAX_VA - PAGE_SIZE;
@@ -38,6 +59,10 @@ pub const GUEST_TRAP_CONTEXT: usize = GUEST_TRAMPOLINE - PAGE_SIZE;
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
 /// 中断切换上下文
 pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE;
+/// Return trap context address of a vCPU, vCPU 0 uses `TRAP_CONTEXT`.
+pub fn trap_context_position(vcpu_id: usize) -> usize {
+    TRAP_CONTEXT - vcpu_id * PAGE_SIZE
+}
 /// Return (bottom, top) of a kernel stack in kernel space.
 pub fn kernel_stack_position(app_id: usize) -> (usize, usize) {
     let top = TRAMPOLINE - app_id * (KERNEL_STACK_SIZE + PAGE_SIZE);
@@ -45,4 +70,9 @@ pub fn kernel_stack_position(app_id: usize) -> (usize, usize) {
     (bottom, top)
 }
 
+/// Return (bottom, top) of the kernel stack of a guest vCPU.
+pub fn vcpu_kernel_stack_position(guest_id: usize, vcpu_id: usize) -> (usize, usize) {
+    kernel_stack_position(guest_id * MAX_VCPUS + vcpu_id)
+}
+
 pub use crate::board::{CLOCK_FREQ, MMIO};
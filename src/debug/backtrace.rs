@@ -1,4 +1,4 @@
-use crate::constants::layout::{TRAMPOLINE, TRAP_CONTEXT};
+use crate::constants::layout::{TRAMPOLINE, MAX_VCPUS, trap_context_position};
 use crate::hypervisor::trap::TrapContext;
 use crate::page_table::{VirtPageNum, PageTable};
 
@@ -57,7 +57,7 @@ pub fn print_hypervisor_backtrace(ctx: &TrapContext) {
         hdebug!("ra -> {:#x}", ra);
         ra = match fp.checked_sub(8) {
             Some(addr) => {
-                if (addr >= 0x8020_0000 && addr <= 0x8800_0000) || (addr >= TRAP_CONTEXT && addr <= TRAMPOLINE) {
+                if (addr >= 0x8020_0000 && addr <= 0x8800_0000) || (addr >= trap_context_position(MAX_VCPUS - 1) && addr <= TRAMPOLINE) {
                     unsafe{ core::ptr::read(addr as *const usize) }
                 }else{
                     break;
@@ -70,7 +70,7 @@ pub fn print_hypervisor_backtrace(ctx: &TrapContext) {
 
         fp = match fp.checked_sub(16) {
             Some(addr) => {
-                if (addr >= 0x8020_0000 && addr <= 0x8800_0000) || (addr >= TRAP_CONTEXT && addr <= TRAMPOLINE) {
+                if (addr >= 0x8020_0000 && addr <= 0x8800_0000) || (addr >= trap_context_position(MAX_VCPUS - 1) && addr <= TRAMPOLINE) {
                     unsafe{ core::ptr::read(addr as *const usize) }
                 }else{
                     break;
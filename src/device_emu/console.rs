@@ -0,0 +1,51 @@
+use arrayvec::ArrayVec;
+
+/// 每行输出的最大长度，超过时直接输出
+const LINE_BUFFER_SIZE: usize = 256;
+
+/// Guest OS 控制台输出缓冲区，按行输出到 host 控制台并添加 `[guest N]` 前缀，
+/// 避免多个 guest 以及 hypervisor 的输出交错在一起
+pub struct GuestConsole {
+    pub line_buffer: ArrayVec<u8, LINE_BUFFER_SIZE>,
+    pub guest_id: usize
+}
+
+impl GuestConsole {
+    pub const fn new(guest_id: usize) -> Self {
+        Self {
+            line_buffer: ArrayVec::new_const(),
+            guest_id
+        }
+    }
+
+    pub fn putchar(&mut self, c: u8) {
+        if c == b'\r' {
+            return;
+        }
+        if c == b'\n' {
+            self.flush();
+            return;
+        }
+        if self.line_buffer.is_full() {
+            self.flush();
+        }
+        self.line_buffer.push(c);
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        bytes.iter().for_each(|&c| self.putchar(c));
+    }
+
+    /// 输出缓冲区中尚未换行的内容(例如等待输入时的提示符)
+    pub fn flush_partial(&mut self) {
+        if !self.line_buffer.is_empty() {
+            self.flush();
+        }
+    }
+
+    /// 输出缓冲区中的内容
+    pub fn flush(&mut self) {
+        crate::console::print_guest_line(self.guest_id, &self.line_buffer);
+        self.line_buffer.clear();
+    }
+}
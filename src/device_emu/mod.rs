@@ -1,31 +1,74 @@
 mod uart;
 mod plic;
 mod virtio;
-pub use uart::Uart;
-pub use plic::HostPlic;
+mod console;
+pub use uart::{Uart, UART_BASE, UART_IRQ, uart_test};
+pub use console::GuestConsole;
+pub use plic::{HostPlic, VirtPlic, PLIC_SOURCES, plic_test};
 pub use virtio::{ VirtIO, is_device_access };
 
 
+/// 由 hypervisor 模拟的 MMIO 设备
+/// 
+/// `offset` 为相对设备基地址的偏移，`width` 为访问的字节数(1、2、4 或 8)
+pub trait MmioDevice {
+    /// 设备寄存器在 guest 物理地址空间中的基地址
+    fn base(&self) -> usize;
+    /// 设备寄存器区域的大小
+    fn size(&self) -> usize;
+    fn read(&mut self, offset: usize, width: usize) -> usize;
+    fn write(&mut self, offset: usize, width: usize, value: usize);
+
+    fn in_region(&self, gpa: usize) -> bool {
+        gpa >= self.base() && gpa < self.base() + self.size()
+    }
+}
+
 /// Software emulated device used in VMM
 pub struct VirtDevice {
     pub qemu_virt_tester: qemu_virt::QemuVirtTester,
-    pub uart: Uart
+    /// 模拟的 16550A 串口
+    pub uart: Uart,
+    /// 虚拟 PLIC
+    pub plic: VirtPlic,
+    /// SBI 控制台输出缓冲区
+    pub console: GuestConsole
 }
 
 impl VirtDevice {
     pub fn new(guest_id: usize) -> Self {
         Self { 
             qemu_virt_tester: qemu_virt::QemuVirtTester::new(),
-            uart: Uart::new(guest_id)
+            uart: Uart::new(guest_id),
+            plic: VirtPlic::new(),
+            console: GuestConsole::new(guest_id)
         }
     }
 
+    /// 根据 guest 物理地址查找模拟的 MMIO 设备，新的模拟设备需要在这里注册
+    pub fn mmio_device(&mut self, gpa: usize) -> Option<&mut dyn MmioDevice> {
+        let devices: [&mut dyn MmioDevice; 3] = [&mut self.qemu_virt_tester, &mut self.uart, &mut self.plic];
+        devices.into_iter().find(|device| device.in_region(gpa))
+    }
+
+    /// guest 物理地址是否属于模拟的 MMIO 设备
+    pub fn is_mmio(&self, gpa: usize) -> bool {
+        let devices: [&dyn MmioDevice; 3] = [&self.qemu_virt_tester, &self.uart, &self.plic];
+        devices.into_iter().any(|device| device.in_region(gpa))
+    }
+
+    /// 将模拟设备的中断线同步到虚拟 PLIC 的挂起位
+    pub fn sync_interrupts(&mut self) {
+        self.plic.set_pending(UART_IRQ, self.uart.interrupt_pending());
+    }
+
 }
 
 
 
 mod qemu_virt {
     use crate::mm::MemoryRegion;
+    use super::MmioDevice;
     /// Software emulated qemu virt test
     pub struct QemuVirtTester {
         pub mmregs: MemoryRegion<u32>
@@ -37,13 +80,35 @@ mod qemu_virt {
                 mmregs: MemoryRegion::new(0x10_0000, 0x1000)
             }
         }
+    }
 
-        pub fn in_region(&self, addr: usize) -> bool {
-            self.mmregs.in_region(addr)
+    impl MmioDevice for QemuVirtTester {
+        fn base(&self) -> usize {
+            self.mmregs.base()
         }
 
-        pub fn base(&self) -> usize {
-            self.mmregs.base()
+        fn size(&self) -> usize {
+            self.mmregs.len()
+        }
+
+        /// 按字节访问所在的 32 位寄存器，8 字节的访问跨越相邻的两个寄存器，超出区域的字节被忽略
+        fn read(&mut self, offset: usize, width: usize) -> usize {
+            let base = self.base();
+            (0..width).filter(|i| offset + i < self.size()).fold(0, |value, i| {
+                let word = self.mmregs[base + ((offset + i) & !0b11)];
+                let byte = (word >> (((offset + i) & 0b11) * 8)) & 0xff;
+                value | (byte as usize) << (i * 8)
+            })
+        }
+
+        fn write(&mut self, offset: usize, width: usize, value: usize) {
+            let (base, size) = (self.base(), self.size());
+            for i in (0..width).filter(|i| offset + i < size) {
+                let addr = base + ((offset + i) & !0b11);
+                let shift = ((offset + i) & 0b11) * 8;
+                let byte = ((value >> (i * 8)) & 0xff) as u32;
+                self.mmregs[addr] = (self.mmregs[addr] & !(0xff << shift)) | byte << shift;
+            }
         }
     }
 }
\ No newline at end of file
@@ -1,19 +1,274 @@
+use crate::constants::layout::MAX_VCPUS;
 use crate::mm::MemoryRegion;
 
+use super::MmioDevice;
+
 
 
 /// ref: https://github.com/mit-pdos/RVirt/blob/HEAD/src/context.rs
-/// hypervisor emulated plic for guest
+/// hypervisor 驱动的物理 PLIC，每个核使用其 S 态上下文 `2 * hart_id + 1`
 pub struct HostPlic {
-    pub claim_clear: MemoryRegion<u32>
+    pub regs: MemoryRegion<u32>
 }
 
 impl HostPlic {
-    pub fn claim_and_clear(&mut self) -> u32 {
-        let claim = self.claim_clear[0];
-        // 设置内存屏障
-        core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
-        self.claim_clear[0] = claim;
-        claim
-    }
-}
\ No newline at end of file
+    pub fn new(base: usize, size: usize) -> Self {
+        Self { regs: MemoryRegion::new(base, size) }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        let addr = self.regs.base() + offset;
+        unsafe{ core::ptr::read_volatile(&self.regs[addr]) }
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        let addr = self.regs.base() + offset;
+        unsafe{ core::ptr::write_volatile(&mut self.regs[addr], value) }
+    }
+
+    fn context_offset(hart: usize) -> usize {
+        CONTEXT_OFFSET + (2 * hart + 1) * CONTEXT_STRIDE
+    }
+
+    pub fn set_priority(&mut self, irq: usize, priority: u32) {
+        self.write(PRIORITY_OFFSET + irq * 4, priority);
+    }
+
+    /// 设置中断源在核 `hart` 的 S 态上下文中是否使能
+    pub fn set_enable(&mut self, hart: usize, irq: usize, enable: bool) {
+        let offset = ENABLE_OFFSET + (2 * hart + 1) * ENABLE_STRIDE + irq / 32 * 4;
+        let mut value = self.read(offset);
+        if enable {
+            value |= 1 << (irq % 32);
+        }else{
+            value &= !(1 << (irq % 32));
+        }
+        self.write(offset, value);
+    }
+
+    pub fn set_threshold(&mut self, hart: usize, threshold: u32) {
+        self.write(Self::context_offset(hart), threshold);
+    }
+
+    /// 认领核 `hart` 上优先级最高的外部中断，没有中断时返回 0
+    pub fn claim(&mut self, hart: usize) -> usize {
+        self.read(Self::context_offset(hart) + 4) as usize
+    }
+
+    /// 完成中断源的处理，之后 PLIC 才会再次发送该中断源
+    pub fn complete(&mut self, hart: usize, irq: usize) {
+        self.write(Self::context_offset(hart) + 4, irq as u32);
+    }
+}
+
+/// guest 看到的 PLIC 基地址(与 qemu virt 相同)
+pub const PLIC_BASE: usize = 0x0c00_0000;
+pub const PLIC_SIZE: usize = 0x60_0000;
+/// 中断源数量，中断源 0 保留
+pub const PLIC_SOURCES: usize = 128;
+/// 每个 vCPU 拥有 M 态与 S 态两个上下文，S 态上下文为 `2 * vcpu_id + 1`
+pub const PLIC_CONTEXTS: usize = 2 * MAX_VCPUS;
+/// 优先级与阈值只实现低 3 位
+const PRIORITY_MASK: u32 = 0b111;
+const SOURCE_WORDS: usize = PLIC_SOURCES / 32;
+
+const PRIORITY_OFFSET: usize = 0;
+const PENDING_OFFSET: usize = 0x1000;
+const ENABLE_OFFSET: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_OFFSET: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+/// 每个 guest 独立的虚拟 PLIC
+///
+/// 中断源被 claim 之后直到 complete 之前不会再次被发送给任何上下文
+pub struct VirtPlic {
+    priority: [u32; PLIC_SOURCES],
+    pending: [u32; SOURCE_WORDS],
+    enable: [[u32; SOURCE_WORDS]; PLIC_CONTEXTS],
+    threshold: [u32; PLIC_CONTEXTS],
+    /// 已经被 claim 但是尚未 complete 的中断源
+    in_service: [u32; SOURCE_WORDS],
+    /// 已经 complete 但是 hypervisor 尚未处理的中断源，用于在 host 上 complete 物理中断源
+    completed: [u32; SOURCE_WORDS]
+}
+
+impl VirtPlic {
+    pub const fn new() -> Self {
+        Self {
+            priority: [0; PLIC_SOURCES],
+            pending: [0; SOURCE_WORDS],
+            enable: [[0; SOURCE_WORDS]; PLIC_CONTEXTS],
+            threshold: [0; PLIC_CONTEXTS],
+            in_service: [0; SOURCE_WORDS],
+            completed: [0; SOURCE_WORDS]
+        }
+    }
+
+    /// 设置中断源的挂起状态
+    pub fn set_pending(&mut self, irq: usize, pending: bool) {
+        if irq == 0 || irq >= PLIC_SOURCES { return; }
+        if pending {
+            self.pending[irq / 32] |= 1 << (irq % 32);
+        }else{
+            self.pending[irq / 32] &= !(1 << (irq % 32));
+        }
+    }
+
+    /// 上下文 `context` 中可以发送的优先级最高的中断源，优先级相同时选择编号最小的中断源
+    fn best_irq(&self, context: usize) -> Option<usize> {
+        (1..PLIC_SOURCES)
+            .filter(|&irq| {
+                let (word, bit) = (irq / 32, 1 << (irq % 32));
+                (self.pending[word] & self.enable[context][word] & !self.in_service[word]) & bit != 0
+            })
+            .filter(|&irq| self.priority[irq] > self.threshold[context])
+            .fold(None, |best: Option<usize>, irq| match best {
+                Some(best) if self.priority[best] >= self.priority[irq] => Some(best),
+                _ => Some(irq)
+            })
+    }
+
+    /// 上下文 `context` 是否有等待处理的外部中断
+    pub fn irq_pending(&self, context: usize) -> bool {
+        context < PLIC_CONTEXTS && self.best_irq(context).is_some()
+    }
+
+    /// 读取 claim 寄存器，返回中断源编号并清除其挂起状态，没有中断时返回 0
+    pub fn claim(&mut self, context: usize) -> usize {
+        match self.best_irq(context) {
+            Some(irq) => {
+                self.pending[irq / 32] &= !(1 << (irq % 32));
+                self.in_service[irq / 32] |= 1 << (irq % 32);
+                irq
+            }
+            None => 0
+        }
+    }
+
+    /// 写入 complete 寄存器，中断源没有在该上下文中使能时忽略，返回是否完成
+    pub fn complete(&mut self, context: usize, irq: usize) -> bool {
+        if irq == 0 || irq >= PLIC_SOURCES { return false; }
+        let (word, bit) = (irq / 32, 1 << (irq % 32));
+        if self.enable[context][word] & bit == 0 || self.in_service[word] & bit == 0 {
+            return false;
+        }
+        self.in_service[word] &= !bit;
+        self.completed[word] |= bit;
+        true
+    }
+
+    /// 取出一个已经被 guest complete 的中断源
+    pub fn take_completed(&mut self) -> Option<usize> {
+        let word = self.completed.iter().position(|&bits| bits != 0)?;
+        let bit = self.completed[word].trailing_zeros() as usize;
+        self.completed[word] &= !(1 << bit);
+        Some(word * 32 + bit)
+    }
+}
+
+impl MmioDevice for VirtPlic {
+    fn base(&self) -> usize {
+        PLIC_BASE
+    }
+
+    fn size(&self) -> usize {
+        PLIC_SIZE
+    }
+
+    fn read(&mut self, offset: usize, _width: usize) -> usize {
+        let offset = offset & !0b11;
+        let value = match offset {
+            PRIORITY_OFFSET..=0xffc => self.priority.get(offset / 4).copied().unwrap_or(0),
+            PENDING_OFFSET..=0x1ffc => self.pending.get((offset - PENDING_OFFSET) / 4).copied().unwrap_or(0),
+            ENABLE_OFFSET..=0x1f_fffc => {
+                let context = (offset - ENABLE_OFFSET) / ENABLE_STRIDE;
+                let word = (offset - ENABLE_OFFSET) % ENABLE_STRIDE / 4;
+                self.enable.get(context).and_then(|enable| enable.get(word)).copied().unwrap_or(0)
+            }
+            CONTEXT_OFFSET.. => {
+                let context = (offset - CONTEXT_OFFSET) / CONTEXT_STRIDE;
+                if context >= PLIC_CONTEXTS { return 0; }
+                match offset % CONTEXT_STRIDE {
+                    0 => self.threshold[context],
+                    4 => self.claim(context) as u32,
+                    _ => 0
+                }
+            }
+            _ => 0
+        };
+        value as usize
+    }
+
+    fn write(&mut self, offset: usize, _width: usize, value: usize) {
+        let offset = offset & !0b11;
+        let value = value as u32;
+        match offset {
+            // 中断源 0 保留
+            0x4..=0xffc => {
+                if let Some(priority) = self.priority.get_mut(offset / 4) {
+                    *priority = value & PRIORITY_MASK;
+                }
+            }
+            // 挂起寄存器只读
+            PENDING_OFFSET..=0x1ffc => {}
+            ENABLE_OFFSET..=0x1f_fffc => {
+                let context = (offset - ENABLE_OFFSET) / ENABLE_STRIDE;
+                let word = (offset - ENABLE_OFFSET) % ENABLE_STRIDE / 4;
+                if let Some(enable) = self.enable.get_mut(context).and_then(|enable| enable.get_mut(word)) {
+                    // 中断源 0 不能被使能
+                    *enable = if word == 0 { value & !1 } else { value };
+                }
+            }
+            CONTEXT_OFFSET.. => {
+                let context = (offset - CONTEXT_OFFSET) / CONTEXT_STRIDE;
+                if context >= PLIC_CONTEXTS { return; }
+                match offset % CONTEXT_STRIDE {
+                    0 => self.threshold[context] = value & PRIORITY_MASK,
+                    4 => { self.complete(context, value as usize); }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[allow(unused)]
+pub fn plic_test() {
+    let mut plic = VirtPlic::new();
+    let context = 1;
+    let enable = ENABLE_OFFSET + context * ENABLE_STRIDE;
+    let claim = CONTEXT_OFFSET + context * CONTEXT_STRIDE + 4;
+    // 中断源 10 与 33，优先级分别为 1 与 2
+    plic.write(10 * 4, 4, 1);
+    plic.write(33 * 4, 4, 2);
+    plic.set_pending(10, true);
+    plic.set_pending(33, true);
+    assert_eq!(plic.read(PENDING_OFFSET, 4), 1 << 10);
+    assert_eq!(plic.read(PENDING_OFFSET + 4, 4), 1 << 1);
+    // 没有使能时不会发送中断
+    assert!(!plic.irq_pending(context));
+    plic.write(enable, 4, 1 << 10);
+    plic.write(enable + 4, 4, 1 << 1);
+    assert!(plic.irq_pending(context));
+    assert!(!plic.irq_pending(context + 2));
+    // 阈值大于等于优先级时屏蔽中断
+    plic.write(CONTEXT_OFFSET + context * CONTEXT_STRIDE, 4, 2);
+    assert_eq!(plic.read(claim, 4), 0);
+    plic.write(CONTEXT_OFFSET + context * CONTEXT_STRIDE, 4, 0);
+    // 按照优先级 claim，claim 之后清除挂起位
+    assert_eq!(plic.read(claim, 4), 33);
+    assert_eq!(plic.read(PENDING_OFFSET + 4, 4), 0);
+    assert_eq!(plic.read(claim, 4), 10);
+    assert!(!plic.irq_pending(context));
+    // complete 之前再次挂起的中断源不会被发送
+    plic.set_pending(33, true);
+    assert!(!plic.irq_pending(context));
+    plic.write(claim, 4, 33);
+    assert_eq!(plic.take_completed(), Some(33));
+    assert_eq!(plic.take_completed(), None);
+    assert!(plic.irq_pending(context));
+    assert_eq!(plic.read(claim, 4), 33);
+    hdebug!("plic test passed!");
+}
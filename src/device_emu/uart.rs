@@ -1,18 +1,69 @@
-use arrayvec::ArrayVec;
+//! ref: https://github.com/mit-pdos/RVirt/blob/HEAD/src/uart.rs
+//!
+//! 模拟 qemu virt 的 16550A 串口，输出按行写入 host 控制台，输入从 host 控制台轮询，
+//! 多个 guest 同时读取串口时 host 的输入由先轮询到的 guest 获得。
+
+use crate::constants::layout::CLOCK_FREQ;
+use crate::sbi::console_getchar;
+
+use super::GuestConsole;
+use super::MmioDevice;
+
+/// guest 看到的串口基地址与中断源(与 qemu virt 相同)
+pub const UART_BASE: usize = 0x1000_0000;
+pub const UART_SIZE: usize = 0x100;
+pub const UART_IRQ: usize = 10;
+
+const FIFO_SIZE: usize = 16;
+/// 开启接收中断时轮询 host 输入的间隔(host 时间)
+const POLL_INTERVAL: usize = CLOCK_FREQ / 100;
+
+// 寄存器偏移
+const RBR_THR_DLL: usize = 0;
+const IER_DLM: usize = 1;
+const IIR_FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+const MSR: usize = 6;
+const SCR: usize = 7;
+
+const IER_RDA: u8 = 1 << 0;
+const IER_THRE: u8 = 1 << 1;
+const IIR_NONE: u8 = 0x01;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+const FCR_FIFO_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const LCR_DLAB: u8 = 1 << 7;
+const MCR_MASK: u8 = 0x1f;
+const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+/// CTS、DSR 与 DCD 总是有效
+const MSR_DEFAULT: u8 = 0xb0;
 
 pub struct Uart {
     pub dlab: bool,
 
     pub divisor_latch: u16,
     pub interrupt_enable: u8,
+    pub line_control: u8,
+    pub modem_control: u8,
+    pub fifo_control: u8,
+    pub scratch: u8,
+    /// 发送保持寄存器为空的中断是否挂起，写入 `THR` 之后立即发送完毕并挂起，读取 `IIR` 时清除
+    pub thre_pending: bool,
 
+    /// 下一次轮询 host 输入的时间
     pub next_interrupt_time: usize,
 
-    pub input_fifo: [u8; 16],
+    pub input_fifo: [u8; FIFO_SIZE],
     pub input_bytes_ready: usize,
 
-    pub line_buffer: ArrayVec<u8, 256>,
-    pub guest_id: usize
+    /// 输出缓冲区
+    pub console: GuestConsole
 }
 
 impl Uart {
@@ -21,12 +72,204 @@ impl Uart {
             dlab: false,
             interrupt_enable: 0,
             divisor_latch: 1,
+            line_control: 0,
+            modem_control: 0,
+            fifo_control: 0,
+            scratch: 0,
+            thre_pending: false,
             next_interrupt_time: 0,
-            input_fifo: [0; 16],
+            input_fifo: [0; FIFO_SIZE],
             input_bytes_ready: 0,
-            line_buffer: ArrayVec::new_const(),
-            guest_id
+            console: GuestConsole::new(guest_id)
+        }
+    }
+
+    /// 将输入的字符放入接收 FIFO，FIFO 已满时丢弃
+    pub fn receive(&mut self, c: u8) -> bool {
+        if self.input_bytes_ready == FIFO_SIZE {
+            return false;
+        }
+        self.input_fifo[self.input_bytes_ready] = c;
+        self.input_bytes_ready += 1;
+        true
+    }
+
+    /// 从 host 控制台读取一个字符放入接收 FIFO，没有输入时返回 `false`
+    fn poll_host(&mut self) -> bool {
+        if self.input_bytes_ready == FIFO_SIZE {
+            return false;
+        }
+        match console_getchar() {
+            c if c > u8::MAX as usize => false,
+            c => self.receive(c as u8)
+        }
+    }
+
+    /// guest 开启接收中断时定期轮询 host 输入，`now` 为 host 时间
+    pub fn poll_input(&mut self, now: usize) {
+        if self.interrupt_enable & IER_RDA == 0 || now < self.next_interrupt_time {
+            return;
+        }
+        self.next_interrupt_time = now + POLL_INTERVAL;
+        // 等待输入之前输出没有换行的提示符
+        self.console.flush_partial();
+        while self.poll_host() {}
+    }
+
+    /// 下一次轮询 host 输入的时间(host 时间)，没有开启接收中断时不需要轮询
+    pub fn next_poll(&self) -> Option<usize> {
+        if self.interrupt_enable & IER_RDA != 0 {
+            Some(self.next_interrupt_time)
+        }else{
+            None
         }
     }
+
+    fn pop_input(&mut self) -> u8 {
+        if self.input_bytes_ready == 0 {
+            return 0;
+        }
+        let c = self.input_fifo[0];
+        self.input_fifo.copy_within(1..self.input_bytes_ready, 0);
+        self.input_bytes_ready -= 1;
+        c
+    }
+
+    /// 当前等待处理的中断，对应 `IIR` 的低 4 位
+    fn interrupt_id(&self) -> u8 {
+        if self.interrupt_enable & IER_RDA != 0 && self.input_bytes_ready > 0 {
+            IIR_RDA
+        }else if self.interrupt_enable & IER_THRE != 0 && self.thre_pending {
+            IIR_THRE
+        }else{
+            IIR_NONE
+        }
+    }
+
+    /// 串口是否向 PLIC 发送中断
+    pub fn interrupt_pending(&self) -> bool {
+        self.interrupt_id() != IIR_NONE
+    }
 }
 
+impl MmioDevice for Uart {
+    fn base(&self) -> usize {
+        UART_BASE
+    }
+
+    fn size(&self) -> usize {
+        UART_SIZE
+    }
+
+    fn read(&mut self, offset: usize, _width: usize) -> usize {
+        let value = match offset {
+            RBR_THR_DLL if self.dlab => self.divisor_latch as u8,
+            RBR_THR_DLL => self.pop_input(),
+            IER_DLM if self.dlab => (self.divisor_latch >> 8) as u8,
+            IER_DLM => self.interrupt_enable,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                // 读取 IIR 时清除发送保持寄存器为空的中断
+                if id == IIR_THRE {
+                    self.thre_pending = false;
+                }
+                if self.fifo_control & FCR_FIFO_ENABLE != 0 { id | IIR_FIFO_ENABLED } else { id }
+            }
+            LCR => self.line_control,
+            MCR => self.modem_control,
+            LSR => {
+                // 轮询模式的驱动通过 LSR 等待输入
+                if self.input_bytes_ready == 0 {
+                    self.console.flush_partial();
+                    self.poll_host();
+                }
+                // 输出立即完成，发送保持寄存器总是为空
+                let ready = if self.input_bytes_ready > 0 { LSR_DR } else { 0 };
+                LSR_THRE | LSR_TEMT | ready
+            }
+            MSR => MSR_DEFAULT,
+            SCR => self.scratch,
+            _ => 0
+        };
+        value as usize
+    }
+
+    fn write(&mut self, offset: usize, _width: usize, value: usize) {
+        let value = value as u8;
+        match offset {
+            RBR_THR_DLL if self.dlab => self.divisor_latch = (self.divisor_latch & 0xff00) | value as u16,
+            RBR_THR_DLL => {
+                self.console.putchar(value);
+                self.thre_pending = true;
+            }
+            IER_DLM if self.dlab => self.divisor_latch = (self.divisor_latch & 0x00ff) | (value as u16) << 8,
+            IER_DLM => {
+                // 开启发送保持寄存器为空的中断时立即产生一次中断
+                if value & IER_THRE != 0 && self.interrupt_enable & IER_THRE == 0 {
+                    self.thre_pending = true;
+                }
+                self.interrupt_enable = value & 0x0f;
+            }
+            IIR_FCR => {
+                if value & FCR_CLEAR_RX != 0 {
+                    self.input_bytes_ready = 0;
+                }
+                self.fifo_control = value;
+            }
+            LCR => {
+                self.line_control = value;
+                self.dlab = value & LCR_DLAB != 0;
+            }
+            MCR => self.modem_control = value & MCR_MASK,
+            SCR => self.scratch = value,
+            _ => {}
+        }
+    }
+}
+
+#[allow(unused)]
+pub fn uart_test() {
+    let mut uart = Uart::new(0);
+    // 设置波特率除数
+    uart.write(LCR, 1, LCR_DLAB as usize | 0x3);
+    uart.write(RBR_THR_DLL, 1, 0x01);
+    uart.write(IER_DLM, 1, 0x02);
+    assert_eq!(uart.divisor_latch, 0x0201);
+    assert_eq!(uart.read(IER_DLM, 1), 0x02);
+    uart.write(LCR, 1, 0x3);
+    assert_eq!(uart.read(LCR, 1), 0x3);
+    assert_eq!(uart.read(IER_DLM, 1), 0);
+    assert_eq!(uart.next_poll(), None);
+    // 开启 FIFO 之后 IIR 的高两位为 1
+    uart.write(IIR_FCR, 1, FCR_FIFO_ENABLE as usize);
+    assert_eq!(uart.read(IIR_FCR, 1), (IIR_FIFO_ENABLED | IIR_NONE) as usize);
+    assert!(!uart.interrupt_pending());
+    // 接收中断，读取 RBR 之后清除
+    uart.write(IER_DLM, 1, IER_RDA as usize);
+    assert_eq!(uart.next_poll(), Some(0));
+    assert!(uart.receive(b'a'));
+    assert!(uart.receive(b'b'));
+    assert!(uart.interrupt_pending());
+    assert_eq!(uart.read(IIR_FCR, 1), (IIR_FIFO_ENABLED | IIR_RDA) as usize);
+    assert_eq!(uart.read(LSR, 1), (LSR_THRE | LSR_TEMT | LSR_DR) as usize);
+    assert_eq!(uart.read(RBR_THR_DLL, 1), b'a' as usize);
+    assert_eq!(uart.read(RBR_THR_DLL, 1), b'b' as usize);
+    assert!(!uart.interrupt_pending());
+    // 开启发送中断时立即产生一次中断，读取 IIR 之后清除
+    uart.write(IER_DLM, 1, (IER_RDA | IER_THRE) as usize);
+    assert!(uart.interrupt_pending());
+    assert_eq!(uart.read(IIR_FCR, 1), (IIR_FIFO_ENABLED | IIR_THRE) as usize);
+    assert!(!uart.interrupt_pending());
+    // 接收中断的优先级高于发送中断
+    uart.write(RBR_THR_DLL, 1, b'x' as usize);
+    uart.receive(b'c');
+    assert_eq!(uart.read(IIR_FCR, 1), (IIR_FIFO_ENABLED | IIR_RDA) as usize);
+    uart.write(IIR_FCR, 1, (FCR_FIFO_ENABLE | FCR_CLEAR_RX) as usize);
+    assert_eq!(uart.read(IIR_FCR, 1), (IIR_FIFO_ENABLED | IIR_THRE) as usize);
+    // FIFO 已满时丢弃输入
+    (0..FIFO_SIZE).for_each(|i| assert!(uart.receive(i as u8)));
+    assert!(!uart.receive(0));
+    uart.write(SCR, 1, 0x5a);
+    assert_eq!(uart.read(SCR, 1), 0x5a);
+    hdebug!("uart test passed!");
+}
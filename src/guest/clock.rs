@@ -0,0 +1,66 @@
+//! Guest OS 的虚拟时钟
+//!
+//! guest 看到的时间为 `(host 时间 - offset) * numer / denom`，guest 创建或重置时从 0 开始计时。
+//! guest 被暂停时虚拟时钟停止，恢复时增加偏移量，因此 guest 不会看到时间跳变。
+//! `time` CSR、`stimecmp` 以及 SBI 时钟使用的都是虚拟时间。
+
+use crate::timer::get_time;
+
+pub struct VirtualClock {
+    /// guest 时间相对 host 时间的偏移(host 时钟周期)
+    offset: usize,
+    /// 时间缩放比例的分子
+    numer: usize,
+    /// 时间缩放比例的分母
+    denom: usize,
+    /// 被暂停时的 host 时间
+    paused_at: Option<usize>
+}
+
+impl VirtualClock {
+    /// 创建从 0 开始计时的虚拟时钟，`(numer, denom)` 为时间缩放比例
+    pub fn new((numer, denom): (usize, usize)) -> Self {
+        assert!(numer != 0 && denom != 0);
+        Self {
+            offset: get_time(),
+            numer,
+            denom,
+            paused_at: None
+        }
+    }
+
+    /// 当前的 guest 时间
+    pub fn now(&self) -> usize {
+        let host_time = self.paused_at.unwrap_or_else(get_time);
+        self.to_guest(host_time)
+    }
+
+    /// host 时间转换为 guest 时间
+    pub fn to_guest(&self, host_time: usize) -> usize {
+        let elapsed = host_time.saturating_sub(self.offset) as u128;
+        (elapsed * self.numer as u128 / self.denom as u128) as usize
+    }
+
+    /// guest 时间转换为 host 时间(向上取整)，用于设置物理时钟，`usize::MAX` 表示没有设置时钟
+    pub fn to_host(&self, guest_time: usize) -> usize {
+        if guest_time == usize::MAX {
+            return usize::MAX;
+        }
+        let elapsed = (guest_time as u128 * self.denom as u128 + self.numer as u128 - 1) / self.numer as u128;
+        (elapsed + self.offset as u128).min(usize::MAX as u128) as usize
+    }
+
+    /// 暂停虚拟时钟
+    pub fn pause(&mut self) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(get_time());
+        }
+    }
+
+    /// 恢复虚拟时钟，暂停期间的时间不计入 guest 时间
+    pub fn resume(&mut self) {
+        if let Some(paused_at) = self.paused_at.take() {
+            self.offset += get_time() - paused_at;
+        }
+    }
+}
@@ -2,10 +2,7 @@ use riscv::addr::BitField;
 
 
 use crate::hypervisor::trap::trap_return;
-use crate::constants::csr::status::{STATUS_SIE_BIT, STATUS_SPIE_BIT, STATUS_SPP_BIT};
-use crate::page_table::PageTable;
-use crate::debug::PageDebug;
-use super::pmap::ShadowPageTables;
+use crate::constants::csr::status::{STATUS_SIE_BIT, STATUS_SPIE_BIT};
 
 
 pub struct ControlRegisters {
@@ -17,13 +14,16 @@ pub struct ControlRegisters {
     /// 中断代理寄存器
     pub sip: usize,
     pub stvec: usize,
-    // scounteren: usize, -- Hard-wired to zero
+    /// guest 用户态能否读取计数器
+    pub scounteren: usize,
+    /// guest 用户态执行环境配置
+    pub senvcfg: usize,
     pub sscratch: usize,
     pub sepc: usize,
     pub scause: usize,
     pub stval: usize,
     pub satp: usize,
-    /// 用于设置 Guest OS 时钟中断
+    /// Guest OS 虚拟时钟的下一次触发时间，由 hypervisor 复用到物理时钟上
     pub mtimecmp: usize
 }
 
@@ -34,6 +34,8 @@ impl ControlRegisters {
             stvec: 0,
             sie: 0,
             sip: 0,
+            scounteren: 0,
+            senvcfg: 0,
             sscratch: 0,
             sepc: 0,
             scause: 0,
@@ -44,23 +46,24 @@ impl ControlRegisters {
     }
 }
 
-pub struct ShadowState<P: PageTable + PageDebug> {
+/// 每个 vCPU 的影子 CSR 状态
+pub struct ShadowState {
     pub csrs: ControlRegisters,
-    /// 影子页表
-    pub shadow_page_tables: ShadowPageTables<P>,
     /// 是否发生中断
     pub interrupt: bool,
-    /// 连续切换页表次数
-    pub conseutive_satp_switch_count: usize
+    /// 虚拟时钟是否已经到期，到期之后直到 guest 重新设置时钟之前不再触发
+    pub timer_fired: bool,
+    /// vCPU 当前是否处于虚拟 S 态，陷入时保存到 `SPP`，`sret` 时从 `SPP` 恢复
+    pub supervisor: bool
 }
 
-impl<P> ShadowState<P> where P: PageTable + PageDebug {
+impl ShadowState {
     pub const fn new() -> Self {
         Self {
             csrs: ControlRegisters::new(),
-            shadow_page_tables: ShadowPageTables::new(),
             interrupt: false,
-            conseutive_satp_switch_count: 0
+            timer_fired: false,
+            supervisor: true
         }
     }
 
@@ -88,8 +91,13 @@ impl<P> ShadowState<P> where P: PageTable + PageDebug {
         self.csrs.sstatus.set_bit(STATUS_SPIE_BIT, true);
     }
 
+    /// 虚拟时钟下一次触发的时间(guest 时间)，已经到期时为 `usize::MAX`
+    pub fn timer_deadline(&self) -> usize {
+        if self.timer_fired { usize::MAX } else { self.csrs.mtimecmp }
+    }
+
     pub fn smode(&self) -> bool { 
-        self.csrs.sstatus.get_bit(STATUS_SPP_BIT)    
+        self.supervisor
     } 
     // 是否开启分页
     pub fn paged(&self) -> bool { self.csrs.satp != 0 }
@@ -0,0 +1,139 @@
+//! vCPU 的浮点寄存器上下文
+//!
+//! guest 运行在 host 用户态，`sstatus.FS` 同时决定了 guest 能否使用浮点单元，因此采用延迟切换：
+//! vCPU 换入时 `FS` 为 Off，第一次执行浮点指令时陷入 hypervisor 再恢复浮点寄存器；
+//! vCPU 换出时只有浮点寄存器被修改过(`FS` 为 Dirty)才需要保存。
+//! guest 看到的 `sstatus.FS` 由影子 `sstatus` 模拟。
+
+use core::arch::asm;
+
+use crate::constants::csr::status::STATUS_FS;
+
+#[repr(C)]
+pub struct FpContext {
+    /// f0 ~ f31
+    pub f: [u64; 32],
+    pub fcsr: usize,
+    /// 物理核上的浮点寄存器比保存的上下文新
+    pub dirty: bool,
+    /// 本次被调度运行期间是否已经使用浮点单元
+    pub active: bool,
+    /// 最近一次恢复浮点寄存器的物理核
+    pub loaded_hart: Option<usize>
+}
+
+impl FpContext {
+    pub const fn new() -> Self {
+        Self {
+            f: [0; 32],
+            fcsr: 0,
+            dirty: false,
+            active: false,
+            loaded_hart: None
+        }
+    }
+
+    /// 将物理核上的浮点寄存器保存到上下文中
+    pub fn save(&mut self) {
+        let fcsr: usize;
+        unsafe {
+            asm!(
+                "csrs sstatus, {1}",
+                "fsd f0, 0({0})",
+                "fsd f1, 8({0})",
+                "fsd f2, 16({0})",
+                "fsd f3, 24({0})",
+                "fsd f4, 32({0})",
+                "fsd f5, 40({0})",
+                "fsd f6, 48({0})",
+                "fsd f7, 56({0})",
+                "fsd f8, 64({0})",
+                "fsd f9, 72({0})",
+                "fsd f10, 80({0})",
+                "fsd f11, 88({0})",
+                "fsd f12, 96({0})",
+                "fsd f13, 104({0})",
+                "fsd f14, 112({0})",
+                "fsd f15, 120({0})",
+                "fsd f16, 128({0})",
+                "fsd f17, 136({0})",
+                "fsd f18, 144({0})",
+                "fsd f19, 152({0})",
+                "fsd f20, 160({0})",
+                "fsd f21, 168({0})",
+                "fsd f22, 176({0})",
+                "fsd f23, 184({0})",
+                "fsd f24, 192({0})",
+                "fsd f25, 200({0})",
+                "fsd f26, 208({0})",
+                "fsd f27, 216({0})",
+                "fsd f28, 224({0})",
+                "fsd f29, 232({0})",
+                "fsd f30, 240({0})",
+                "fsd f31, 248({0})",
+                "frcsr {2}",
+                "csrc sstatus, {1}",
+                in(reg) self.f.as_mut_ptr(),
+                in(reg) STATUS_FS,
+                out(reg) fcsr
+            );
+        }
+        self.fcsr = fcsr;
+        self.dirty = false;
+    }
+
+    /// 将上下文恢复到物理核 `hart` 的浮点寄存器中
+    pub fn restore(&mut self, hart: usize) {
+        unsafe {
+            asm!(
+                "csrs sstatus, {1}",
+                "fld f0, 0({0})",
+                "fld f1, 8({0})",
+                "fld f2, 16({0})",
+                "fld f3, 24({0})",
+                "fld f4, 32({0})",
+                "fld f5, 40({0})",
+                "fld f6, 48({0})",
+                "fld f7, 56({0})",
+                "fld f8, 64({0})",
+                "fld f9, 72({0})",
+                "fld f10, 80({0})",
+                "fld f11, 88({0})",
+                "fld f12, 96({0})",
+                "fld f13, 104({0})",
+                "fld f14, 112({0})",
+                "fld f15, 120({0})",
+                "fld f16, 128({0})",
+                "fld f17, 136({0})",
+                "fld f18, 144({0})",
+                "fld f19, 152({0})",
+                "fld f20, 160({0})",
+                "fld f21, 168({0})",
+                "fld f22, 176({0})",
+                "fld f23, 184({0})",
+                "fld f24, 192({0})",
+                "fld f25, 200({0})",
+                "fld f26, 208({0})",
+                "fld f27, 216({0})",
+                "fld f28, 224({0})",
+                "fld f29, 232({0})",
+                "fld f30, 240({0})",
+                "fld f31, 248({0})",
+                "fscsr {2}",
+                "csrc sstatus, {1}",
+                in(reg) self.f.as_ptr(),
+                in(reg) STATUS_FS,
+                in(reg) self.fcsr
+            );
+        }
+        self.loaded_hart = Some(hart);
+    }
+
+    /// vCPU 被换出时保存被修改过的浮点寄存器，下次运行时重新延迟恢复
+    pub fn switch_out(&mut self) {
+        if self.dirty {
+            self.save();
+        }
+        self.active = false;
+    }
+}
@@ -0,0 +1,123 @@
+//! hypocaust 半虚拟化接口使用的 guest 状态
+//!
+//! guest 可以通过 hypocaust 扩展查询 hypervisor 统计信息，也可以注册一个共享页，
+//! hypervisor 在 guest 的 vCPU 被调度运行时将最新的信息写入共享页。
+
+use crate::debug::PageDebug;
+use crate::page_table::PageTable;
+use crate::constants::layout::{PAGE_SIZE, MAX_VCPUS};
+
+use super::{GuestKernel, is_guest_memory};
+use super::sbi::*;
+
+/// 共享页格式版本
+pub const SHARED_PAGE_VERSION: usize = 2;
+
+/// hypervisor 为每个 guest 记录的统计信息
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct GuestStats {
+    /// 陷入 hypervisor 的次数
+    pub traps: usize,
+    /// SBI 调用次数
+    pub sbi_calls: usize,
+    /// 模拟的特权指令数量
+    pub emulated_instructions: usize,
+    /// 由 hypervisor 处理的页错误次数
+    pub page_faults: usize,
+    /// 时钟中断次数
+    pub timer_interrupts: usize,
+    /// vCPU 被调度到物理核上运行的次数
+    pub vcpu_switches: usize
+}
+
+impl GuestStats {
+    pub const fn new() -> Self {
+        Self {
+            traps: 0,
+            sbi_calls: 0,
+            emulated_instructions: 0,
+            page_faults: 0,
+            timer_interrupts: 0,
+            vcpu_switches: 0
+        }
+    }
+
+    /// 根据统计信息编号获取统计值
+    pub fn get(&self, stat: usize) -> Option<usize> {
+        match stat {
+            HYPOCAUST_STAT_TRAPS => Some(self.traps),
+            HYPOCAUST_STAT_SBI_CALLS => Some(self.sbi_calls),
+            HYPOCAUST_STAT_EMULATED_INSTRUCTIONS => Some(self.emulated_instructions),
+            HYPOCAUST_STAT_PAGE_FAULTS => Some(self.page_faults),
+            HYPOCAUST_STAT_TIMER_INTERRUPTS => Some(self.timer_interrupts),
+            HYPOCAUST_STAT_VCPU_SWITCHES => Some(self.vcpu_switches),
+            _ => None
+        }
+    }
+}
+
+/// 共享页的内存布局，位于共享页的起始位置
+#[repr(C)]
+pub struct SharedInfo {
+    pub version: usize,
+    pub guest_id: usize,
+    pub vcpu_count: usize,
+    pub stats: GuestStats,
+    /// 每个 vCPU 被窃取的时间(guest 时间)
+    pub stolen_time: [usize; MAX_VCPUS]
+}
+
+impl<P> GuestKernel<P> where P: PageTable + PageDebug {
+    /// 注册共享页(guest 物理地址)，`usize::MAX` 表示取消注册，地址无效时返回 `false`。
+    /// 共享页必须位于 guest 内存中，guest 地址空间中的跳板页与 Trap Context 属于 hypervisor
+    pub fn register_shared_page(&mut self, paddr: usize) -> bool {
+        if paddr == usize::MAX {
+            self.shared_page = None;
+            return true;
+        }
+        if paddr % PAGE_SIZE != 0 || !is_guest_memory(paddr) || !is_guest_memory(paddr + core::mem::size_of::<SharedInfo>() - 1) {
+            return false;
+        }
+        self.shared_page = Some(paddr);
+        self.update_shared_page();
+        true
+    }
+
+    /// 将最新的信息写入共享页
+    pub fn update_shared_page(&self) {
+        if let Some(host_pa) = self.shared_page.and_then(|paddr| self.translate_guest_paddr(paddr)) {
+            let info = unsafe{ &mut *(host_pa as *mut SharedInfo) };
+            info.version = SHARED_PAGE_VERSION;
+            info.guest_id = self.guest_id;
+            info.vcpu_count = self.vcpus.len();
+            info.stats = self.stats;
+            self.vcpus.iter().for_each(|vcpu| info.stolen_time[vcpu.vcpu_id] = vcpu.stolen_time);
+        }
+    }
+}
+
+#[allow(unused)]
+pub fn shared_page_test() {
+    use crate::constants::layout::{TRAMPOLINE, GUEST_KERNEL_VIRT_END, trap_context_position};
+    use crate::hypervisor::HYPOCAUST;
+    let mut inner = HYPOCAUST.lock();
+    let guest = inner.as_mut().unwrap().guests[0].as_mut().unwrap();
+    // hypervisor 映射在 guest 地址空间中的跳板页与 Trap Context 不能作为共享页
+    assert!(!guest.register_shared_page(TRAMPOLINE));
+    (0..MAX_VCPUS).for_each(|vcpu_id| assert!(!guest.register_shared_page(trap_context_position(vcpu_id))));
+    assert!(guest.shared_page.is_none());
+    // 共享页必须按页对齐
+    let paddr = GUEST_KERNEL_VIRT_END - PAGE_SIZE;
+    assert!(!guest.register_shared_page(paddr + 8));
+    // guest 内存的最后一页，测试结束后恢复原来的内容
+    let info = guest.translate_guest_paddr(paddr).unwrap() as *mut SharedInfo;
+    let saved = unsafe{ core::ptr::read(info) };
+    assert!(guest.register_shared_page(paddr));
+    assert_eq!(unsafe{ (*info).version }, SHARED_PAGE_VERSION);
+    assert_eq!(unsafe{ (*info).guest_id }, guest.guest_id);
+    assert!(guest.register_shared_page(usize::MAX));
+    assert!(guest.shared_page.is_none());
+    unsafe{ core::ptr::write(info, saved) };
+    hdebug!("shared page test passed!");
+}
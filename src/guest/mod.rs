@@ -1,12 +1,14 @@
 use crate::constants::csr::sie::{SEIE, STIE, SSIE, STIE_BIT};
-use crate::constants::csr::sip::SSIP;
-use crate::constants::csr::status::STATUS_SIE_BIT;
+use crate::constants::csr::sip::{SSIP, SEIP_BIT};
+use crate::constants::csr::status::{STATUS_SIE_BIT, STATUS_FS, STATUS_XS, STATUS_UXL, STATUS_UXL_64, SSTATUS_WRITABLE};
+use crate::constants::csr::counteren::COUNTEREN_MASK;
+use crate::constants::csr::envcfg::FIOM;
 use crate::debug::PageDebug;
 use crate::hypervisor::HYPERVISOR_MEMORY;
-use crate::page_table::{VirtAddr, PhysPageNum, PageTable};
-use crate::mm::{MemorySet, MapPermission};
-use crate::hypervisor::trap::{TrapContext, trap_handler};
-use crate::constants::layout::{TRAP_CONTEXT, kernel_stack_position, GUEST_KERNEL_VIRT_START};
+use crate::page_table::{VirtAddr, PageTable};
+use crate::mm::{MemorySet, MapPermission, load_guest_kernel_image};
+use crate::hypervisor::trap::{GuestFault, GuestResult, set_virtual_timer};
+use crate::constants::layout::{MAX_VCPUS, GUEST_KERNEL_VIRT_START, trap_context_position, vcpu_kernel_stack_position};
 use crate::constants::csr;
 use crate::device_emu::VirtDevice;
 
@@ -15,115 +17,244 @@ pub mod switch;
 pub mod context;
 mod pmap;
 pub mod sbi;
+pub mod vcpu;
+mod hypercall;
+mod clock;
+mod fpu;
 
-use context::TaskContext;
+use alloc::vec::Vec;
 use riscv::addr::BitField;
 
 pub use self::context::ShadowState;
-pub use self::pmap::{ ShadowPageTables, PageTableRoot, gpa2hpa, hpa2gpa };
+pub use self::vcpu::{VCpu, VCpuState};
+pub use self::hypercall::{GuestStats, SharedInfo, shared_page_test};
+pub use self::clock::VirtualClock;
+pub use self::fpu::FpContext;
+pub use self::pmap::{ ShadowPageTables, PageTableRoot, AccessType, ShadowView, gpa2hpa, hpa2gpa, is_guest_memory };
+
+/// 创建 Guest Kernel 时的配置
+#[derive(Clone, Copy, Debug)]
+pub struct GuestConfig {
+    /// Guest OS 入口地址(GVA)
+    pub entry: usize,
+    /// guest 虚拟时钟相对 host 时钟的缩放比例(分子, 分母)
+    pub time_scale: (usize, usize)
+}
+
+impl Default for GuestConfig {
+    fn default() -> Self {
+        Self { entry: GUEST_KERNEL_VIRT_START, time_scale: (1, 1) }
+    }
+}
+
+/// Guest Kernel 生命周期状态
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GuestState {
+    /// 可被调度运行
+    Ready,
+    /// 正在运行
+    Running,
+    /// 被暂停，恢复之前不会被调度
+    Paused,
+    /// Guest OS 请求重启，所有 vCPU 让出处理器后重新加载镜像
+    Rebooting,
+    /// Guest OS 已关机
+    Halted,
+    /// Guest OS 发生了无法处理的错误
+    Crashed
+}
 
 /// Guest Kernel 结构体
 pub struct GuestKernel<P: PageTable + PageDebug> {
     pub memory_set: MemorySet<P>,
-    pub trap_cx_ppn: PhysPageNum,
-    pub task_cx: TaskContext,
-    pub shadow_state: ShadowState<P>,
+    /// 虚拟 CPU，下标即为 guest 看到的 hart id
+    pub vcpus: Vec<VCpu>,
+    /// 当前正在处理的 vCPU，由 hypervisor 在访问 guest 之前设置
+    pub active_vcpu: usize,
+    pub state: GuestState,
+    /// guest 关机或崩溃时请求的状态，由 hypervisor 在陷入处理结束时通过 `Hypervisor::stop_guest` 停止 guest
+    pub stop_request: Option<GuestState>,
+    /// 影子页表，由所有 vCPU 共享
+    pub shadow_page_tables: ShadowPageTables<P>,
+    /// 连续切换页表次数
+    pub conseutive_satp_switch_count: usize,
     pub guest_id: usize,
+    /// Guest OS 镜像，重置时重新加载
+    pub image: &'static [u8],
+    pub config: GuestConfig,
     /// Guest OS 是否运行在 S mode
     pub smode: bool,
     /// Virtual emulated device in qemu
     pub virt_device: VirtDevice,
+    /// hypervisor 统计信息
+    pub stats: GuestStats,
+    /// guest 通过 hypocaust 扩展注册的共享页(guest 物理地址)
+    pub shared_page: Option<usize>,
+    /// guest 虚拟时钟
+    pub clock: VirtualClock
 }
 
 impl<P> GuestKernel<P> where P: PageDebug + PageTable {
-    pub fn new(memory_set: MemorySet<P>, guest_id: usize) -> Self {
-        // 获取中断上下文的物理地址
-        let mut hypervisor_memory = HYPERVISOR_MEMORY.exclusive_access();
-        let trap_cx_ppn = memory_set
-            .translate(VirtAddr::from(TRAP_CONTEXT).into())
-            .unwrap()
-            .ppn();
-        // 获取内核栈地址
-        let (kernel_stack_bottom, kernel_stack_top) = kernel_stack_position(guest_id);
-        // 将内核栈地址进行映射
-        hypervisor_memory.insert_framed_area(
-            kernel_stack_bottom.into(),
-            kernel_stack_top.into(),
-            MapPermission::R | MapPermission::W,
-        );
+    pub fn new(memory_set: MemorySet<P>, guest_id: usize, image: &'static [u8], config: GuestConfig) -> Self {
+        let vcpus = (0..MAX_VCPUS).map(|vcpu_id| {
+            // 获取中断上下文的物理地址
+            let trap_cx_ppn = memory_set
+                .translate(VirtAddr::from(trap_context_position(vcpu_id)).into())
+                .unwrap()
+                .ppn();
+            // 获取内核栈地址
+            let (kernel_stack_bottom, kernel_stack_top) = vcpu_kernel_stack_position(guest_id, vcpu_id);
+            // 将内核栈地址进行映射
+            HYPERVISOR_MEMORY.exclusive_access().insert_framed_area(
+                kernel_stack_bottom.into(),
+                kernel_stack_top.into(),
+                MapPermission::R | MapPermission::W,
+            );
+            VCpu::new(vcpu_id, trap_cx_ppn)
+        }).collect();
         let mut guest_kernel = Self { 
             memory_set,
-            trap_cx_ppn,
-            task_cx: TaskContext::goto_trap_return(kernel_stack_top),
-            shadow_state: ShadowState::new(),
+            vcpus,
+            active_vcpu: 0,
+            state: GuestState::Ready,
+            stop_request: None,
+            shadow_page_tables: ShadowPageTables::new(guest_id),
+            conseutive_satp_switch_count: 0,
             guest_id,
+            image,
+            config,
             smode: true,
             virt_device: VirtDevice::new(guest_id), 
+            stats: GuestStats::new(),
+            shared_page: None,
+            clock: VirtualClock::new(config.time_scale)
         };
-        // 设置 Guest OS `sstatus` 的 `SPP`
-        let mut sstatus = riscv::register::sstatus::read();
-        sstatus.set_spp(riscv::register::sstatus::SPP::Supervisor);
-        guest_kernel.shadow_state.csrs.sstatus = sstatus.bits();
-        // 获取中断上下文的地址
-        let trap_cx : &mut TrapContext = guest_kernel.trap_cx_ppn.get_mut();
-        *trap_cx = TrapContext::app_init_context(
-            GUEST_KERNEL_VIRT_START,
-            0,
-            hypervisor_memory.token(),
-            kernel_stack_top,
-            trap_handler as usize,
-        );
+        // 只有 vCPU 0 从入口地址开始运行，其余 vCPU 由 guest 通过 SBI HSM 扩展启动
+        guest_kernel.vcpus[0].start(config.entry, 0);
         guest_kernel
     }
 
+    /// 重置 Guest OS：重新加载镜像，清除影子状态，并从入口地址重新开始运行
+    pub fn reset(&mut self) {
+        // 重新将镜像拷贝到 guest 物理内存段，映射关系与之前相同，不需要重新建立内存空间
+        load_guest_kernel_image(self.image, self.guest_id);
+        // 释放所有影子页表，影子页表区域中的页在重新分配时清零
+        self.shadow_page_tables = ShadowPageTables::new(self.guest_id);
+        self.conseutive_satp_switch_count = 0;
+        self.stop_request = None;
+        self.smode = true;
+        self.virt_device = VirtDevice::new(self.guest_id);
+        self.stats = GuestStats::new();
+        self.shared_page = None;
+        self.clock = VirtualClock::new(self.config.time_scale);
+        self.vcpus.iter_mut().for_each(|vcpu| vcpu.stop());
+        self.vcpus[0].start(self.config.entry, 0);
+    }
+
+    /// 是否可以被调度运行
+    pub fn runnable(&self) -> bool {
+        self.state == GuestState::Ready || self.state == GuestState::Running
+    }
+
+    /// 当前正在处理的 vCPU
+    pub fn vcpu(&self) -> &VCpu {
+        &self.vcpus[self.active_vcpu]
+    }
+
+    pub fn vcpu_mut(&mut self) -> &mut VCpu {
+        &mut self.vcpus[self.active_vcpu]
+    }
+
+    /// 当前 vCPU 的影子 CSR 状态
+    pub fn shadow_state(&self) -> &ShadowState {
+        &self.vcpu().shadow_state
+    }
+
+    pub fn shadow_state_mut(&mut self) -> &mut ShadowState {
+        &mut self.vcpu_mut().shadow_state
+    }
+
+    /// 根据虚拟 PLIC 的状态设置每个 vCPU 影子 `sip` 中的 `SEIP`，
+    /// vCPU 的 S 态外部中断由 PLIC 上下文 `2 * vcpu_id + 1` 发送
+    pub fn update_external_interrupts(&mut self) {
+        self.virt_device.sync_interrupts();
+        let plic = &self.virt_device.plic;
+        for vcpu in self.vcpus.iter_mut() {
+            let pending = plic.irq_pending(2 * vcpu.vcpu_id + 1);
+            let state = &mut vcpu.shadow_state;
+            if pending && !state.csrs.sip.get_bit(SEIP_BIT) {
+                state.interrupt = true;
+                vcpu.kick = true;
+            }
+            state.csrs.sip.set_bit(SEIP_BIT, pending);
+        }
+    }
+
     /// 根据 `PageTableRoot` mode 来获取对应的 shadow page table token
     pub fn get_user_token(&self) -> usize {
         match self.shadow() {
             PageTableRoot::GPA => self.memory_set.token(), 
-            PageTableRoot::GVA => if self.shadow_state.csrs.satp == self.shadow_state.shadow_page_tables.guest_satp.unwrap() 
-                                    { self.shadow_state.shadow_page_tables.page_tables[1].unwrap() }
-                                    else{ self.shadow_state.shadow_page_tables.page_tables[2].unwrap() },
-            PageTableRoot::UVA => self.shadow_state.shadow_page_tables.page_tables[2].unwrap(),  
+            // 每个 vCPU 可能使用不同的页表与权限视图，因此根据当前 vCPU 的 satp 与视图查找影子页表
+            PageTableRoot::GVA | PageTableRoot::UVA => self.current_shadow_page_table().unwrap().token()
         }
     }
 
     /// 用来检查应当使用哪一级的影子页表
     pub fn shadow(&self) -> PageTableRoot {
-        if (self.shadow_state.csrs.satp >> 60) & 0xf == 0 {
+        if (self.shadow_state().csrs.satp >> 60) & 0xf == 0 {
             PageTableRoot::GPA
-        }else if !self.shadow_state.smode() {
+        }else if !self.shadow_state().smode() {
             PageTableRoot::UVA
         }else {
             PageTableRoot::GVA
         }
     }
 
-    pub fn get_csr(&self, csr: usize) -> usize {
-        let shadow_state = &self.shadow_state;
-        match csr {
+    /// 读取影子 CSR，未知的 CSR 返回 `GuestFault::UnknownCsr`
+    pub fn get_csr(&self, csr: usize) -> GuestResult<usize> {
+        let shadow_state = self.shadow_state();
+        let val = match csr {
             csr::sstatus => shadow_state.csrs.sstatus,
             csr::stvec => shadow_state.csrs.stvec,
             csr::sie => shadow_state.csrs.sie,
+            csr::sip => shadow_state.csrs.sip,
+            csr::scounteren => shadow_state.csrs.scounteren,
+            csr::senvcfg => shadow_state.csrs.senvcfg,
             csr::sscratch => shadow_state.csrs.sscratch,
             csr::sepc => shadow_state.csrs.sepc,
             csr::scause => shadow_state.csrs.scause,
             csr::stval => shadow_state.csrs.stval,
             csr::satp => shadow_state.csrs.satp,
-            _ => unreachable!(),
-        }
+            csr::stimecmp => shadow_state.csrs.mtimecmp,
+            // 只读计数器
+            csr::cycle => riscv::register::cycle::read(),
+            csr::time => self.clock.now(),
+            csr::instret => riscv::register::instret::read(),
+            // 没有实现的性能计数器固定为 0
+            csr::hpmcounter3..=csr::hpmcounter31 => 0,
+            _ => return Err(GuestFault::UnknownCsr(csr)),
+        };
+        Ok(val)
     }
 
-    pub fn set_csr(&mut self, csr: usize, val: usize) {
-        let shadow_state = &mut self.shadow_state;
+    /// 写入影子 CSR，按照特权级规范对 WARL 字段进行掩码，未知的 CSR 返回 `GuestFault::UnknownCsr`
+    pub fn set_csr(&mut self, csr: usize, val: usize) -> GuestResult {
+        let shadow_state = self.shadow_state_mut();
         match csr {
             csr::sstatus => { 
                 if val.get_bit(STATUS_SIE_BIT) {
                     // Enabling interruots might casue one to happen right away
                     shadow_state.interrupt = true;
                 }
-                shadow_state.csrs.sstatus  = val
+                let mut sstatus = (shadow_state.csrs.sstatus & !SSTATUS_WRITABLE) | (val & SSTATUS_WRITABLE);
+                sstatus = (sstatus & !STATUS_UXL) | STATUS_UXL_64;
+                // `SD` 表示 `FS` 或 `XS` 为 Dirty
+                let dirty = sstatus & STATUS_FS == STATUS_FS || sstatus & STATUS_XS == STATUS_XS;
+                sstatus.set_bit(63, dirty);
+                shadow_state.csrs.sstatus = sstatus;
              }
-            csr::stvec => shadow_state.csrs.stvec = val,
+            // 支持 Direct 与 Vectored 模式，保留的模式按照 Direct 处理
+            csr::stvec => shadow_state.csrs.stvec = if val & 0b11 > 1 { val & !0b11 } else { val },
             csr::sie => { 
                 let value = val & (SEIE | STIE | SSIE);
                 if !shadow_state.csrs.sie & value != 0{
@@ -132,7 +263,7 @@ impl<P> GuestKernel<P> where P: PageDebug + PageTable {
                 if value.get_bit(STIE_BIT) {
                     unsafe{ riscv::register::sie::set_stimer() };
                 }
-                shadow_state.csrs.sie = val;
+                shadow_state.csrs.sie = value;
             }
             csr::sip => {
                 if val & SSIP != 0 {
@@ -140,8 +271,11 @@ impl<P> GuestKernel<P> where P: PageDebug + PageTable {
                 }
                 shadow_state.csrs.sip = (shadow_state.csrs.sip & !SSIP) | (val & SSIP);
             }
+            csr::scounteren => shadow_state.csrs.scounteren = val & COUNTEREN_MASK,
+            csr::senvcfg => shadow_state.csrs.senvcfg = val & FIOM,
             csr::sscratch => shadow_state.csrs.sscratch = val,
-            csr::sepc => shadow_state.csrs.sepc = val,
+            // 支持压缩指令，`sepc` 最低位固定为 0
+            csr::sepc => shadow_state.csrs.sepc = val & !1,
             csr::scause => shadow_state.csrs.scause = val,
             csr::stval => shadow_state.csrs.stval = val,
             csr::satp => { 
@@ -149,15 +283,47 @@ impl<P> GuestKernel<P> where P: PageDebug + PageTable {
                 match (satp >> 60) & 0xf {
                     0 => shadow_state.csrs.satp = satp, 
                     8 => {
-                        // 获取 guest kernel 
+                        // 根页表必须位于 guest 内存中
+                        let root_gpa = (satp & 0xfff_ffff_ffff) << 12;
+                        if !is_guest_memory(root_gpa) {
+                            return Err(GuestFault::InvalidPageTableAddress(root_gpa));
+                        }
+                        // 切换页表时建立当前视图下的影子页表，返回 guest 之前切换根页表
                         shadow_state.csrs.satp = satp;
-                        self.make_shadow_page_table(satp);
+                        let view = ShadowView::new(self.shadow_state());
+                        if self.shadow_page_tables.shadow_page_table(satp, view).is_some() {
+                            self.conseutive_satp_switch_count += 1;
+                        }
+                        self.make_shadow_page_table(satp, view)?;
                     }
-                    _ => panic!("Install page table with unsupported mode?") 
+                    // 写入不支持的模式时整个写操作无效，guest 可以据此探测支持的分页模式
+                    mode => hwarning!("guest {} unsupported satp mode {}", self.guest_id, mode)
                 }
             }
-            _ => unreachable!()
+            csr::stimecmp => set_virtual_timer(self, val),
+            _ => return Err(GuestFault::UnknownCsr(csr))
+        }
+        Ok(())
+    }
+
+    /// guest 当前特权级能否访问 CSR
+    /// 
+    /// CSR 编号的 [9:8] 位为可以访问的最低特权级，[11:10] 位为 `0b11` 时只读；
+    /// guest 用户态读取计数器时还需要 `scounteren` 中对应的位被设置
+    pub fn csr_accessible(&self, csr: usize, write: bool) -> bool {
+        let smode = self.shadow_state().smode();
+        if write && csr.get_bits(10..12) == 0b11 {
+            return false;
+        }
+        match csr.get_bits(8..10) {
+            0 => {},
+            1 if smode => {},
+            _ => return false
+        }
+        if !smode && (csr::cycle..=csr::hpmcounter31).contains(&csr) {
+            return self.shadow_state().csrs.scounteren.get_bit(csr - csr::cycle);
         }
+        true
     }
     
 
@@ -1,14 +1,19 @@
-use alloc::collections::{VecDeque, BTreeMap};
+use alloc::collections::{VecDeque, BTreeMap, BTreeSet};
 use alloc::vec::Vec;
 use core::cell::UnsafeCell;
 
+use crate::constants::csr::status::{STATUS_SUM, STATUS_MXR};
 use crate::debug::PageDebug;
 use crate::device_emu::is_device_access;
 use crate::hypervisor::HYPERVISOR_MEMORY;
-use crate::page_table::{PageTable, VirtPageNum, PageTableEntry, PhysPageNum, PTEFlags};
-use crate::constants::layout::{GUEST_KERNEL_VIRT_START, TRAMPOLINE, TRAP_CONTEXT};
+use crate::mm::MemorySet;
+use crate::sbi::remote_sfence_vma;
+use crate::page_table::{PageTable, PageTableSv39, VirtPageNum, PageTableEntry, PhysPageNum, PTEFlags};
+use crate::constants::layout::{GUEST_KERNEL_VIRT_START, KERNEL_SPACE, TRAMPOLINE, MAX_VCPUS, PAGE_SIZE, spt_position, trap_context_position};
 
-use super::GuestKernel;
+use crate::hypervisor::trap::{GuestFault, GuestResult};
+
+use super::{GuestKernel, ShadowState, VCpu, VCpuState};
 
 /// 内存信息，用于帮助做地址映射
 #[allow(unused)]
@@ -17,7 +22,69 @@ mod segment_layout {
     pub const SPT_OFFSET: usize = 0x10000_0000 - 0x8000_0000;
 }
 
+/// `sfence.vma` 逐页同步的最大页数，超过时同步整个页表
+const SFENCE_MAX_PAGES: usize = 64;
+
+/// 影子根页表中由 hypervisor 使用的页表项(跳板页与 Trap Context 所在的最高 1 GiB)，不与 guest 页表同步
+const HYPERVISOR_ROOT_INDEX: usize = 511;
+
+/// guest 的访存类型
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessType {
+    Load,
+    Store,
+    Fetch
+}
+
+/// 影子页表的权限视图
+///
+/// guest 内核运行在 host 用户态，因此影子页表的叶子项总是设置 `U` 位，
+/// guest 自身的 `U` 位以及 `sstatus` 的 `SUM`、`MXR` 通过改写叶子项的权限模拟
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ShadowView {
+    /// guest 处于用户态，只能访问 `U` 页
+    pub user: bool,
+    /// guest 内核态可以读写(不能执行) `U` 页
+    pub sum: bool,
+    /// 可执行的页同时可读
+    pub mxr: bool
+}
 
+impl ShadowView {
+    /// guest 内核态且没有设置 `SUM` 与 `MXR`
+    pub const fn supervisor() -> Self {
+        Self { user: false, sum: false, mxr: false }
+    }
+
+    /// 根据 vCPU 的特权级与 `sstatus` 获得视图
+    pub fn new(state: &ShadowState) -> Self {
+        let sstatus = state.csrs.sstatus;
+        Self {
+            user: !state.smode(),
+            sum: state.smode() && sstatus & STATUS_SUM != 0,
+            mxr: sstatus & STATUS_MXR != 0
+        }
+    }
+
+    /// 根据 guest 叶子页表项的权限计算影子页表项的权限，该视图下无法访问时返回 `None`
+    pub fn leaf_flags(&self, flags: PTEFlags) -> Option<PTEFlags> {
+        let mut new_flags = flags;
+        if self.user {
+            if !flags.contains(PTEFlags::U) { return None; }
+        }else if flags.contains(PTEFlags::U) {
+            if !self.sum { return None; }
+            // S 态不能执行用户页
+            new_flags.remove(PTEFlags::X);
+        }
+        if self.mxr && flags.contains(PTEFlags::X) {
+            new_flags.insert(PTEFlags::R);
+        }
+        if !new_flags.intersects(PTEFlags::R | PTEFlags::W | PTEFlags::X) {
+            return None;
+        }
+        Some(new_flags | PTEFlags::U)
+    }
+}
 
 /// 页表(影子页表类型)
 #[derive(Copy, Clone, Eq, PartialEq)]
@@ -31,256 +98,292 @@ pub enum PageTableRoot {
 }
 
 pub struct ShadowPageTables<P: PageTable + PageDebug> {
-    /// all shadow page tables (satp, spt)
-    pub spts: UnsafeCell<BTreeMap<usize, P>>,
-    /// guest kernel installed shadow page table
-    pub page_tables: [Option<usize>; 3],
-    /// kernel guest page table token
-    pub guest_satp: Option<usize>
+    /// all shadow page tables ((satp, view), spt)，同一个 guest 页表在每个权限视图下拥有独立的影子页表
+    pub spts: UnsafeCell<BTreeMap<(usize, ShadowView), P>>,
+    /// guest 页表页(GPA)在各个视图下的影子页表页(HPA)，多个页表共享的 guest 页表页在同一视图下共享影子页表页
+    mirrors: BTreeMap<usize, Vec<(ShadowView, usize)>>,
+    /// guest 页表页(GPA)以及所在的页表级数
+    pt_pages: BTreeMap<usize, usize>,
+    /// 尚未在 guest 内核态视图中设置为只读的 guest 页表页
+    unprotected: Vec<usize>,
+    /// 影子页表区域中下一个未使用的页，之前的页都已经分配给影子页表
+    next_page: usize,
+    /// 影子页表区域被回收的次数，影子页表区域分为两半，偶数代与奇数代交替使用
+    pub generation: usize,
+    /// 上一代的影子根页表，其它核上的 vCPU 可能仍在使用，保留到下一次回收时释放
+    retired: BTreeMap<(usize, ShadowView), P>,
+    guest_id: usize
 }
 
 impl<P> ShadowPageTables<P> where P: PageDebug + PageTable {
-    pub const fn new() -> Self {
+    pub fn new(guest_id: usize) -> Self {
         Self {
             spts: UnsafeCell::new(BTreeMap::new()),
-            page_tables: [None; 3],
-            guest_satp: None
+            mirrors: BTreeMap::new(),
+            pt_pages: BTreeMap::new(),
+            unprotected: Vec::new(),
+            next_page: spt_position(guest_id).0,
+            generation: 0,
+            retired: BTreeMap::new(),
+            guest_id
         }
     }
 
-    pub fn spts(&self) -> &mut BTreeMap<usize, P> {
-        unsafe{ &mut *self.spts.get() }
+    /// 当前代使用的影子页表区域
+    fn region(&self) -> (usize, usize) {
+        let (start, end) = spt_position(self.guest_id);
+        let half = (end - start) / 2;
+        let start = start + (self.generation % 2) * half;
+        (start, start + half)
     }
 
-    pub fn push(&self, satp: usize, spt: P) {
-        let inner = self.spts();
-        inner.insert(satp, spt);
+    /// 当前代的影子页表区域用完时切换到另一半区域并丢弃所有影子页表，之后的影子页表在使用时重新建立。
+    /// 另一半区域中的根页表可能仍被其它核上的 vCPU 使用，调用者需要保证这些 vCPU 都已经切换到当前代
+    fn recycle(&mut self) {
+        // 上一代的根页表已经没有 vCPU 使用，释放为其映射跳板页与 Trap Context 分配的页帧
+        let retired = core::mem::replace(&mut self.retired, core::mem::take(self.spts()));
+        drop(retired);
+        self.mirrors.clear();
+        self.pt_pages.clear();
+        self.unprotected.clear();
+        self.generation += 1;
+        self.next_page = self.region().0;
     }
 
+    pub fn spts(&self) -> &mut BTreeMap<(usize, ShadowView), P> {
+        unsafe{ &mut *self.spts.get() }
+    }
 
-    pub fn shadow_page_table(&self, satp: usize) -> Option<&mut P> {
+    pub fn push(&self, satp: usize, view: ShadowView, spt: P) {
         let inner = self.spts();
-        inner.get_mut(&satp)
+        inner.insert((satp, view), spt);
     }
 
-    pub fn guest_page_table(&self) -> Option<&mut P> {
+    pub fn shadow_page_table(&self, satp: usize, view: ShadowView) -> Option<&mut P> {
         let inner = self.spts();
-        if let Some(guest_satp) = self.guest_satp {
-            inner.get_mut(&guest_satp)
-        }else{
-            None
-        }
+        inner.get_mut(&(satp, view))
     }
 
-    pub fn install_root(&mut self, spt_token: usize, mode: PageTableRoot) {
-        match mode {
-            PageTableRoot::GPA => self.page_tables[0] = Some(spt_token),
-            PageTableRoot::GVA => self.page_tables[1] = Some(spt_token),
-            PageTableRoot::UVA => self.page_tables[2] = Some(spt_token)
+    /// 从影子页表区域中分配一页并清零，区域中只有分配过的页会被使用，销毁或重置 guest 时不需要清空整个区域
+    fn alloc_page(&mut self) -> GuestResult<usize> {
+        let (_, spt_end) = self.region();
+        if self.next_page >= spt_end {
+            return Err(GuestFault::ShadowPageTableExhausted);
         }
+        let page = self.next_page;
+        self.next_page += PAGE_SIZE;
+        PhysPageNum::from(page >> 12).get_bytes_array().fill(0);
+        Ok(page)
     }
 
-}
-
-pub fn gpa2hpa(va: usize, hart_id: usize) -> usize {
-    va + (hart_id + 1) * segment_layout::HART_SEGMENT_SIZE
-}
-
-pub fn hpa2gpa(pa: usize, hart_id: usize) -> usize {
-    pa - (hart_id + 1) * segment_layout::HART_SEGMENT_SIZE
-}
-
-pub fn gpt2spt(va: usize, hart_id: usize) -> usize {
-    va + segment_layout::SPT_OFFSET + hart_id * segment_layout::HART_SEGMENT_SIZE
-}
-
-pub fn page_table_mode<P: PageTable>(page_table: P, hart_id: usize) -> PageTableRoot {
-    if page_table.translate_guest(VirtPageNum::from(GUEST_KERNEL_VIRT_START >> 12), hart_id).is_some() {
-        return PageTableRoot::GVA
+    /// guest 页表页 `gpa` 在视图 `view` 下的影子页表页，不存在时分配新页，新页中的页表项在访问时再同步
+    fn mirror(&mut self, gpa: usize, view: ShadowView) -> GuestResult<PhysPageNum> {
+        let found = self.mirrors.get(&gpa)
+            .and_then(|pages| pages.iter().find(|&&(page_view, _)| page_view == view))
+            .map(|&(_, page)| page);
+        let page = match found {
+            Some(page) => page,
+            None => {
+                let page = self.alloc_page()?;
+                self.mirrors.entry(gpa).or_default().push((view, page));
+                page
+            }
+        };
+        Ok(PhysPageNum::from(page >> 12))
     }
-    PageTableRoot::UVA
-}
-
 
-
-fn update_pte_readonly<P: PageTable>(vpn: VirtPageNum, spt: &mut P) -> bool {
-    if let Some(pte) = spt.find_pte(vpn) {
-        if pte.writable() | pte.executable() {
-            *pte = PageTableEntry::new(pte.ppn(), PTEFlags::R | PTEFlags::U | PTEFlags::V);
+    /// 记录第 `level` 级的 guest 页表页
+    fn add_page_table(&mut self, gpa: usize, level: usize) {
+        if !self.pt_pages.contains_key(&gpa) {
+            self.pt_pages.insert(gpa, level);
+            self.unprotected.push(gpa);
         }
-        true
-    }else{
-        false
     }
-}
 
-fn clear_page_table<P: PageTable>(spt: &mut P, va: usize, hart_id: usize) {
-    let mut drop = true;
-    let guest_ppn = PhysPageNum::from(gpa2hpa(va, hart_id) >> 12);
-    let guest_ptes = guest_ppn.get_pte_array();
-    guest_ptes.iter().for_each(|&pte| { 
-        if pte.bits != 0 { drop = false; }
-    });
-    if drop {
-        // htracking!("Drop the page table guest ppn -> {:#x}", guest_ppn.0);
-        // 将影子页表设置为可读可写
-        if let Some(spt_pte) = spt.find_pte(VirtPageNum::from(va >> 12)) {
-            *spt_pte = PageTableEntry::new(spt_pte.ppn(), PTEFlags::R | PTEFlags::W | PTEFlags::U | PTEFlags::V);
+    /// guest 页表页中的页表项被全部清除时不再将其视为页表页，返回是否释放
+    fn release_page_table(&mut self, gpa: usize) -> bool {
+        if !self.pt_pages.contains_key(&gpa) || guest_ptes(gpa, self.guest_id).iter().any(|pte| pte.bits != 0) {
+            return false;
         }
+        self.pt_pages.remove(&gpa).is_some()
     }
-}
 
-/// 收集所有页表的虚拟页号
-pub fn collect_page_table_vpns<P: PageTable>(hart_id: usize, satp: usize) -> Vec<VirtPageNum> {
-    let guest_root_pa  = (satp & 0xfff_ffff_ffff) << 12;
-
-    // 遍历所有页表项
-    let mut queue = VecDeque::new();
-    let mut buffer = Vec::new();
-    // 非叶子所在的虚拟页号
-    let mut non_leaf_vpns = Vec::new();
-    let vpn = VirtPageNum::from(guest_root_pa >> 12);
-    queue.push_back(vpn);
-
-    for walk in 0..3 {
-        // 遍历三级页表
-        while !queue.is_empty() {
-            // 获得 guest pte 的虚拟页号
-            let guest_page_table_vpn = queue.pop_front().unwrap();
-            // 收集所有非叶子节点 `vpn`，用于设置为只读
-            non_leaf_vpns.push(guest_page_table_vpn);
-            // 获得 guest pte 的物理页号
-            let guest_page_table_ppn = PhysPageNum::from(gpa2hpa(guest_page_table_vpn.0 << 12, hart_id) >> 12);
-            // 获得 guest pte 页表项内容
-            let guest_ptes = guest_page_table_ppn.get_pte_array();
-            for guest_pte in guest_ptes.iter(){
-                if guest_pte.is_valid() && walk < 2 {
-                    // 非叶子页表项
-                    buffer.push(VirtPageNum::from(guest_pte.ppn().0));
-                }else if guest_pte.is_valid() && walk == 2 {
-                }
+    /// 根据第 `level` 级的 guest 页表项构造视图 `view` 下的影子页表项，
+    /// 非叶子项指向下一级 guest 页表页在同一视图下的影子页表页
+    fn shadow_pte(&mut self, guest_pte: PageTableEntry, level: usize, view: ShadowView) -> GuestResult<PageTableEntry> {
+        if !guest_pte.is_valid() {
+            return Ok(PageTableEntry::empty());
+        }
+        if guest_pte.readable() || guest_pte.writable() || guest_pte.executable() {
+            // 不支持大页，guest 访问时触发页错误
+            if level < 2 {
+                return Ok(PageTableEntry::empty());
+            }
+            let host_pte = shadow_leaf_pte(guest_pte, self.guest_id, view);
+            // guest 内核映射的页表页设置为只读，写入时陷入并同步影子页表
+            if !view.user && self.pt_pages.contains_key(&(guest_pte.ppn().0 << 12)) {
+                return Ok(readonly_pte(host_pte));
             }
+            return Ok(host_pte);
         }
-        while !buffer.is_empty() {
-            queue.push_back(buffer.pop().unwrap());
+        // 最后一级不能指向页表，下一级页表不在 guest 内存中时 guest 访问时触发页错误
+        let child = guest_pte.ppn().0 << 12;
+        if level == 2 || !is_guest_memory(child) {
+            return Ok(PageTableEntry::empty());
         }
+        self.add_page_table(child, level + 1);
+        Ok(PageTableEntry::new(self.mirror(child, view)?, guest_pte.flags()))
     }
-    non_leaf_vpns
-    
-}
 
-pub fn synchronize_page_table<P: PageTable>(hart_id: usize, satp: usize) {
-    let guest_root_pa  = (satp & 0xfff_ffff_ffff) << 12;
-
-    // 遍历所有页表项
-    let mut queue = VecDeque::new();
-    let mut buffer = Vec::new();
-    let vpn = VirtPageNum::from(guest_root_pa >> 12);
-    queue.push_back(vpn);
-
-    for walk in 0..3 {
-        // 遍历三级页表
-        while !queue.is_empty() {
-            // 获得 guest pte 的虚拟页号
-            let guest_page_table_vpn = queue.pop_front().unwrap();
-            // 收集所有非叶子节点 `vpn`，用于设置为只读
-            let host_page_table_ppn = PhysPageNum::from(gpt2spt(guest_page_table_vpn.0 << 12, hart_id) >> 12);
-            // 获得 guest pte 的物理页号
-            let guest_page_table_ppn = PhysPageNum::from(gpa2hpa(guest_page_table_vpn.0 << 12, hart_id) >> 12);
-            // 获得 guest pte 页表项内容
-            let guest_ptes = guest_page_table_ppn.get_pte_array();
-            // 获得 host pte 页表项内容
-            let host_ptes = host_page_table_ppn.get_pte_array();
-            for (index, guest_pte) in guest_ptes.iter().enumerate() {
-                if guest_pte.is_valid() && walk < 2 {
-                    // 非叶子页表项
-                    buffer.push(VirtPageNum::from(guest_pte.ppn().0));
-                    // 构造 host pte
-                    let host_pte = PageTableEntry::new(PhysPageNum::from(gpt2spt(guest_pte.ppn().0 << 12, hart_id) >> 12) , guest_pte.flags());
-                    host_ptes[index] = host_pte;
-                }else if guest_pte.is_valid() && walk == 2 {
-                    let host_pte = PageTableEntry::new(PhysPageNum::from(gpa2hpa(guest_pte.ppn().0 << 12, hart_id) >> 12) , guest_pte.flags() | PTEFlags::U);
-                    host_ptes[index] = host_pte;
+    /// 根据 guest 页表同步视图 `view` 下的整个影子页表
+    fn sync_all(&mut self, satp: usize, view: ShadowView) -> GuestResult {
+        let root = (satp & 0xfff_ffff_ffff) << 12;
+        self.add_page_table(root, 0);
+        let mut visited = BTreeSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back((root, 0));
+        while let Some((page, level)) = queue.pop_front() {
+            if !visited.insert(page) {
+                continue;
+            }
+            let host_ptes = self.mirror(page, view)?.get_pte_array();
+            for (index, &guest_pte) in guest_ptes(page, self.guest_id).iter().enumerate() {
+                if level == 0 && index == HYPERVISOR_ROOT_INDEX {
+                    continue;
                 }
+                let host_pte = self.shadow_pte(guest_pte, level, view)?;
+                if is_page_table(host_pte) {
+                    queue.push_back((guest_pte.ppn().0 << 12, level + 1));
+                }
+                host_ptes[index] = host_pte;
             }
         }
-        while !buffer.is_empty() {
-            queue.push_back(buffer.pop().unwrap());
-        }
+        Ok(())
     }
-}
 
-/// 用于初始化影子页表同步所有页表项(仅在最开始时使用)
-pub fn initialize_shadow_page_table<P: PageTable>(hart_id: usize, satp: usize, mode: PageTableRoot, guest_spt: Option<&mut P>) -> Option<P> {
-    let guest_root_pa  = (satp & 0xfff_ffff_ffff) << 12;
-    let host_root_pa = gpt2spt(guest_root_pa, hart_id);
-    // 获取 `guest SPT`
-    let mut empty_spt = P::from_token(0);
-    let guest_spt = match mode {
-        PageTableRoot::GVA => { &mut empty_spt },
-        PageTableRoot::UVA => if let Some(spt) = guest_spt { spt } else { panic!() }
-        _ => unreachable!() 
-    };
-    // 遍历所有页表项
-    let mut queue = VecDeque::new();
-    let mut buffer = Vec::new();
-    // 非叶子所在的虚拟页号
-    let mut non_leaf_vpns = Vec::new();
-    let vpn = VirtPageNum::from(guest_root_pa >> 12);
-    queue.push_back(vpn);
-    for walk in 0..3 {
-        // 遍历三级页表
-        while !queue.is_empty() {
-            // 获得 guest pte 的虚拟页号
-            let guest_page_table_vpn = queue.pop_front().unwrap();
-            // 收集所有非叶子节点 `vpn`，用于设置为只读
-            non_leaf_vpns.push(guest_page_table_vpn);
-            let host_page_table_ppn = PhysPageNum::from(gpt2spt(guest_page_table_vpn.0 << 12, hart_id) >> 12);
-            // 获得 guest pte 的物理页号
-            let guest_page_table_ppn = PhysPageNum::from(gpa2hpa(guest_page_table_vpn.0 << 12, hart_id) >> 12);
-            // 获得 guest pte 页表项内容
-            let guest_ptes = guest_page_table_ppn.get_pte_array();
-            // 获得 host pte 页表项内容
-            let host_ptes = host_page_table_ppn.get_pte_array();
-            for (index, guest_pte) in guest_ptes.iter().enumerate() {
-                if guest_pte.is_valid() && walk < 2 {
-                    // 非叶子页表项
-                    buffer.push(VirtPageNum::from(guest_pte.ppn().0));
-                    // 构造 host pte
-                    let host_pte = PageTableEntry::new(PhysPageNum::from(gpt2spt(guest_pte.ppn().0 << 12, hart_id) >> 12) , guest_pte.flags());
-                    host_ptes[index] = host_pte;
-                }else if guest_pte.is_valid() && walk == 2 {
-                    let host_pte;
-                    if !is_device_access(guest_pte.ppn().0 << 12) {
-                        host_pte = PageTableEntry::new(PhysPageNum::from(gpa2hpa(guest_pte.ppn().0 << 12, hart_id) >> 12) , guest_pte.flags() | PTEFlags::U);
-                    }else{
-                        host_pte = PageTableEntry::new(PhysPageNum::from(guest_pte.ppn().0) , guest_pte.flags() | PTEFlags::U);
-                    }
-                    host_ptes[index] = host_pte;
-                }
+    /// 根据 guest 页表重新同步视图 `view` 下 `va` 所在的影子页表项，
+    /// guest 页表中无效的页表项在影子页表中同样设置为无效
+    fn sync_entry(&mut self, satp: usize, va: usize, view: ShadowView) -> GuestResult {
+        let idxs = VirtPageNum::from(va >> 12).indexes();
+        let mut page = (satp & 0xfff_ffff_ffff) << 12;
+        for (level, &index) in idxs.iter().enumerate() {
+            if level == 0 && index == HYPERVISOR_ROOT_INDEX {
+                return Ok(());
+            }
+            let guest_pte = guest_ptes(page, self.guest_id)[index];
+            let host_pte = self.shadow_pte(guest_pte, level, view)?;
+            self.mirror(page, view)?.get_pte_array()[index] = host_pte;
+            if !is_page_table(host_pte) {
+                return Ok(());
             }
+            page = guest_pte.ppn().0 << 12;
         }
-        while !buffer.is_empty() {
-            queue.push_back(buffer.pop().unwrap());
+        Ok(())
+    }
+
+    /// guest 写入页表页 `gpa` 的第 `index` 项之后，同步所有视图下对应的影子页表项
+    fn write_entry(&mut self, gpa: usize, index: usize, guest_pte: PageTableEntry) -> GuestResult {
+        let level = match self.pt_pages.get(&gpa) {
+            Some(&level) => level,
+            None => return Ok(())
+        };
+        if level == 0 && index == HYPERVISOR_ROOT_INDEX {
+            return Ok(());
         }
+        let views: Vec<ShadowView> = self.mirrors.get(&gpa)
+            .map_or(Vec::new(), |pages| pages.iter().map(|&(view, _)| view).collect());
+        for view in views {
+            let host_pte = self.shadow_pte(guest_pte, level, view)?;
+            self.mirror(gpa, view)?.get_pte_array()[index] = host_pte;
+        }
+        Ok(())
     }
-    let mut host_shadow_page_table = PageTable::from_ppn(PhysPageNum::from(host_root_pa >> 12));
-    non_leaf_vpns.iter().for_each(|&vpn| {
-        match mode {
-            PageTableRoot::GVA => {
-                update_pte_readonly(vpn, &mut host_shadow_page_table);
-            },
-            PageTableRoot::UVA => {
-                update_pte_readonly(vpn, guest_spt);
-            },
-            _ => unreachable!()
+
+    /// 在 guest 内核态视图的影子页表中将 `pages` 设置为只读(guest 内核恒等映射页表页)，
+    /// 其它物理核上可能缓存了可写的地址翻译，修改之后刷新所有物理核的 TLB
+    fn protect(&self, pages: &[usize]) {
+        let mut changed = false;
+        for ((_, view), spt) in self.spts().iter() {
+            if view.user {
+                continue;
+            }
+            for &gpa in pages {
+                changed |= update_pte_readonly(VirtPageNum::from(gpa >> 12), spt);
+            }
+        }
+        if changed {
+            remote_sfence_vma(0, usize::MAX, 0, usize::MAX);
         }
-    });
-    Some(host_shadow_page_table)
+    }
+
+    /// 将新发现的 guest 页表页设置为只读
+    fn flush_protection(&mut self) {
+        let pages: Vec<usize> = core::mem::take(&mut self.unprotected).into_iter()
+            .filter(|gpa| self.pt_pages.contains_key(gpa))
+            .collect();
+        self.protect(&pages);
+    }
+}
+
+pub fn gpa2hpa(va: usize, hart_id: usize) -> usize {
+    va + (hart_id + 1) * segment_layout::HART_SEGMENT_SIZE
+}
+
+pub fn hpa2gpa(pa: usize, hart_id: usize) -> usize {
+    pa - (hart_id + 1) * segment_layout::HART_SEGMENT_SIZE
+}
+
+/// guest 物理地址是否位于 guest 的内存中
+pub fn is_guest_memory(gpa: usize) -> bool {
+    gpa >= GUEST_KERNEL_VIRT_START && gpa < GUEST_KERNEL_VIRT_START + KERNEL_SPACE
 }
 
+/// guest 页表页 `gpa` 中的页表项
+fn guest_ptes(gpa: usize, hart_id: usize) -> &'static mut [PageTableEntry] {
+    PhysPageNum::from(gpa2hpa(gpa, hart_id) >> 12).get_pte_array()
+}
+
+/// 是否为指向下一级页表的页表项
+fn is_page_table(pte: PageTableEntry) -> bool {
+    pte.is_valid() && !(pte.readable() || pte.writable() || pte.executable())
+}
 
+/// 将有效的叶子项设置为只读
+fn readonly_pte(pte: PageTableEntry) -> PageTableEntry {
+    if pte.writable() || pte.executable() {
+        PageTableEntry::new(pte.ppn(), PTEFlags::R | PTEFlags::U | PTEFlags::V)
+    }else{
+        pte
+    }
+}
 
+/// 将影子页表中 `vpn` 对应的叶子项设置为只读，返回是否修改了页表项
+fn update_pte_readonly<P: PageTable>(vpn: VirtPageNum, spt: &P) -> bool {
+    match spt.find_pte(vpn) {
+        Some(pte) if pte.writable() || pte.executable() => {
+            *pte = readonly_pte(*pte);
+            true
+        }
+        _ => false
+    }
+}
+
+/// 根据 guest 叶子页表项与权限视图构造影子页表项，直通设备的地址直接映射，
+/// 模拟设备的地址以及当前视图下不可访问的页不映射，guest 访问时触发页错误并由 hypervisor 处理
+fn shadow_leaf_pte(guest_pte: PageTableEntry, hart_id: usize, view: ShadowView) -> PageTableEntry {
+    let gpa = guest_pte.ppn().0 << 12;
+    let flags = match view.leaf_flags(guest_pte.flags()) {
+        Some(flags) => flags,
+        None => return PageTableEntry::empty()
+    };
+    if is_device_access(gpa) {
+        PageTableEntry::new(PhysPageNum::from(guest_pte.ppn().0) , flags)
+    }else if is_guest_memory(gpa) {
+        PageTableEntry::new(PhysPageNum::from(gpa2hpa(gpa, hart_id) >> 12) , flags)
+    }else{
+        PageTableEntry::empty()
+    }
+}
 
 impl<P> GuestKernel<P> where P: PageDebug + PageTable {
     /// GPA -> HPA
@@ -294,6 +397,80 @@ impl<P> GuestKernel<P> where P: PageDebug + PageTable {
         None
     }
 
+    /// GVA -> GPA，遍历当前 vCPU 的 guest 页表(支持大页)，没有开启分页时 GVA 即为 GPA
+    pub fn guest_vaddr_to_paddr(&self, vaddr: usize) -> Option<usize> {
+        if (self.shadow_state().csrs.satp >> 60) & 0xf == 0 {
+            return Some(vaddr);
+        }
+        self.guest_leaf_pte(vaddr).map(|(_, paddr)| paddr)
+    }
+
+    /// 遍历 guest 页表获得 `vaddr` 对应的叶子页表项以及 guest 物理地址，guest 没有开启分页时返回 `None`
+    pub fn guest_leaf_pte(&self, vaddr: usize) -> Option<(PageTableEntry, usize)> {
+        let satp = self.shadow_state().csrs.satp;
+        if (satp >> 60) & 0xf == 0 {
+            return None;
+        }
+        let idxs = VirtPageNum::from(vaddr >> 12).indexes();
+        let mut page_table = (satp & 0xfff_ffff_ffff) << 12;
+        for (level, &index) in idxs.iter().enumerate() {
+            let pte_pa = self.translate_guest_paddr(page_table + index * core::mem::size_of::<PageTableEntry>())?;
+            let pte = unsafe{ core::ptr::read(pte_pa as *const PageTableEntry) };
+            if !pte.is_valid() {
+                return None;
+            }
+            if pte.readable() || pte.executable() {
+                // 叶子页表项，非最后一级时为大页
+                let page_size = PAGE_SIZE << (9 * (2 - level));
+                return Some((pte, ((pte.ppn().0 << 12) & !(page_size - 1)) | (vaddr & (page_size - 1))));
+            }
+            page_table = pte.ppn().0 << 12;
+        }
+        None
+    }
+
+    /// guest 页表是否允许当前 vCPU 以 `access` 的方式访问 `vaddr`，同时检查 `U` 位与 `sstatus` 的 `SUM`、`MXR`，
+    /// 没有开启分页时可以访问 guest 内存以及模拟的设备
+    pub fn guest_access_permitted(&self, vaddr: usize, access: AccessType) -> bool {
+        if (self.shadow_state().csrs.satp >> 60) & 0xf == 0 {
+            return is_guest_memory(vaddr) || self.virt_device.is_mmio(vaddr);
+        }
+        let pte = match self.guest_leaf_pte(vaddr) {
+            Some((pte, _)) => pte,
+            None => return false
+        };
+        let sstatus = self.shadow_state().csrs.sstatus;
+        let privilege = if self.shadow_state().smode() {
+            // S 态不能执行用户页，只有设置 `SUM` 时才能读写用户页
+            !pte.is_user() || (access != AccessType::Fetch && sstatus & STATUS_SUM != 0)
+        }else{
+            pte.is_user()
+        };
+        privilege && match access {
+            AccessType::Load => pte.readable() || (pte.executable() && sstatus & STATUS_MXR != 0),
+            AccessType::Store => pte.writable(),
+            AccessType::Fetch => pte.executable()
+        }
+    }
+
+    /// 获得 guest 物理地址 `[paddr, paddr + len)` 对应的 host 内存(按页拆分)，
+    /// 存在不在 guest 内存中的地址时返回 `None`，guest 地址空间中的跳板页与 Trap Context 属于 hypervisor，不能被访问
+    pub fn guest_paddr_slices(&self, paddr: usize, len: usize) -> Option<Vec<&'static mut [u8]>> {
+        let end = paddr.checked_add(len)?;
+        let mut slices = Vec::new();
+        let mut start = paddr;
+        while start < end {
+            let page_end = ((start & !(PAGE_SIZE - 1)) + PAGE_SIZE).min(end);
+            if !is_guest_memory(start) {
+                return None;
+            }
+            let host_pa = self.translate_guest_paddr(start)?;
+            slices.push(unsafe{ core::slice::from_raw_parts_mut(host_pa as *mut u8, page_end - start) });
+            start = page_end;
+        }
+        Some(slices)
+    }
+
     /// GVA -> HPA
     pub fn translate_guest_vaddr(&self, vaddr: usize) -> Option<usize> {
         let offset = vaddr & 0xfff;
@@ -310,7 +487,7 @@ impl<P> GuestKernel<P> where P: PageDebug + PageTable {
     }
 
     pub fn translate_guest_vpte(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
-        if let Some(spt) = self.shadow_state.shadow_page_tables.shadow_page_table(self.shadow_state.csrs.satp) {
+        if let Some(spt) = self.current_shadow_page_table() {
             // 由于 GHA 与 GPA 是同等映射的，因此翻译成的物理地址可以直接当虚拟地址用
             spt.translate(vpn)
         }else{
@@ -330,161 +507,186 @@ impl<P> GuestKernel<P> where P: PageDebug + PageTable {
         None
     }
 
-    /// 根据 satp 构建影子页表
-    /// 需要将 GVA -> HPA
-    pub fn make_shadow_page_table(&mut self, satp: usize) {
-        // 根据 satp 获取 guest kernel 根页表的物理地址
-        let hart_id = self.guest_id;
-        let root_gpa = (satp & 0xfff_ffff_ffff) << 12;
-        let root_hppn = PhysPageNum::from(gpa2hpa(root_gpa, hart_id) >> 12);
-        let gpt = P::from_ppn(root_hppn);
+    /// 当前 vCPU 的 satp 与权限视图对应的影子页表
+    pub fn current_shadow_page_table(&self) -> Option<&mut P> {
+        let state = self.shadow_state();
+        self.shadow_page_tables.shadow_page_table(state.csrs.satp, ShadowView::new(state))
+    }
+
+    /// 获得 guest 地址空间中 `trap_cx_va` 处 Trap Context 的 host 物理页号
+    fn trap_context_ppn(&self, hypervisor_memory: &MemorySet<PageTableSv39>, trap_cx_va: usize) -> GuestResult<PhysPageNum> {
+        let trapctx_hva = self.translate_guest_paddr(trap_cx_va).ok_or(GuestFault::MissingHypervisorMapping(trap_cx_va))?;
+        hypervisor_memory.translate(VirtPageNum::from(trapctx_hva >> 12))
+            .map(|pte| pte.ppn())
+            .ok_or(GuestFault::MissingHypervisorMapping(trap_cx_va))
+    }
 
+    /// 为影子页表映射跳板页与每个 vCPU 的 Trap Context，
+    /// 只有 ASID 不同的页表共享影子根页表，已经映射过的页跳过
+    fn map_hypervisor_pages(&self, spt: &mut P) -> GuestResult {
         let hypervisor_memory = HYPERVISOR_MEMORY.exclusive_access();
-        if self.shadow_state.shadow_page_tables.shadow_page_table(satp).is_none() {
-            // 如果影子页表中没有发现，新建影子页表
-            let mut spt;
-            let mode;
-            // 根据页表是否可读内核地址空间判断是 `GVA` 还是 `UVA`
-            match page_table_mode(gpt.clone(), hart_id) {
-                PageTableRoot::GVA => {
-                    // 将 mode 设置为 `GVA`
-                    mode = PageTableRoot::GVA;
-                    spt = initialize_shadow_page_table::<P>(hart_id, satp, mode, None).unwrap();
-                    self.shadow_state.shadow_page_tables.guest_satp = Some(satp);
-
-                    assert_eq!(spt.translate(VirtPageNum::from(0x10001)).unwrap().ppn().0, 0x10001);
-                }
-                PageTableRoot::UVA => {
-                    // 将 mode 设置为 `UVA`
-                    mode = PageTableRoot::UVA;
-                    // 同步 guest spt,即将用户页表设置为只读
-                    let guest_spt = self.shadow_state.shadow_page_tables.guest_page_table().unwrap();   
-                    spt = initialize_shadow_page_table::<P>(hart_id, satp, mode, Some(guest_spt)).unwrap();              
-                    
-                }
-                _ => unreachable!()
+        let mapped = |spt: &P, va: usize| spt.translate(VirtPageNum::from(va >> 12)).map_or(false, |pte| pte.is_valid());
+        if !mapped(spt, TRAMPOLINE) {
+            let trampoline_hppn = hypervisor_memory.translate(VirtPageNum::from(TRAMPOLINE >> 12))
+                .ok_or(GuestFault::MissingHypervisorMapping(TRAMPOLINE))?.ppn();
+            spt.map(VirtPageNum::from(TRAMPOLINE >> 12), trampoline_hppn, PTEFlags::R | PTEFlags::X);
+        }
+        // 每个 vCPU 拥有自己的 Trap Context
+        for vcpu_id in 0..MAX_VCPUS {
+            let trap_cx_va = trap_context_position(vcpu_id);
+            if !mapped(spt, trap_cx_va) {
+                let trapctx_hppn = self.trap_context_ppn(&hypervisor_memory, trap_cx_va)?;
+                spt.map(VirtPageNum::from(trap_cx_va >> 12), trapctx_hppn, PTEFlags::R | PTEFlags::W);
             }
+        }
+        Ok(())
+    }
 
-            // 为 `SPT` 映射跳板页
-            // 无论是 guest spt 还是 user spt 都要映射跳板页与 Trap Context
-            let trampoline_hppn = hypervisor_memory.translate(VirtPageNum::from(TRAMPOLINE >> 12)).unwrap().ppn();
-            spt.map(VirtPageNum::from(TRAMPOLINE >> 12), trampoline_hppn, PTEFlags::R | PTEFlags::X);
+    /// 影子页表区域用完时回收影子页表区域，返回是否回收。
+    /// 其它核上运行的 vCPU 仍在使用上一代的根页表时不能覆盖上一代的区域，
+    /// 回收之后通知其它核上运行的 vCPU 陷入，在返回 guest 之前切换到新的根页表
+    fn recycle_shadow_page_tables(&mut self) -> bool {
+        let generation = self.shadow_page_tables.generation;
+        let active = self.active_vcpu;
+        let others = |vcpu: &&mut VCpu| vcpu.vcpu_id != active && vcpu.state == VCpuState::Running;
+        if self.vcpus.iter_mut().filter(others).any(|vcpu| vcpu.spt_generation != generation) {
+            return false;
+        }
+        hwarning!("guest {} shadow page table region exhausted, recycle", self.guest_id);
+        self.shadow_page_tables.recycle();
+        self.vcpus.iter_mut().filter(others).for_each(|vcpu| vcpu.kick = true);
+        true
+    }
 
-            let trapctx_hvpn = VirtPageNum::from(self.translate_guest_paddr(TRAP_CONTEXT).unwrap() >> 12);
-            let trapctx_hppn = hypervisor_memory.translate(trapctx_hvpn).unwrap().ppn();
-            spt.map(VirtPageNum::from(TRAP_CONTEXT >> 12), trapctx_hppn, PTEFlags::R | PTEFlags::W);
+    /// 影子页表区域用完时回收区域，回收之后所有影子页表在返回 guest 之前重新建立，不需要继续同步，返回 `recycled`
+    fn recycle_on_exhaustion<T>(&mut self, result: GuestResult<T>, recycled: T) -> GuestResult<T> {
+        match result {
+            Err(GuestFault::ShadowPageTableExhausted) if self.recycle_shadow_page_tables() => Ok(recycled),
+            result => result
+        }
+    }
 
-            // hdebug!("Make new SPT(satp -> {:#x}, spt -> {:#x}) ", satp, spt.token());
-            self.shadow_state.shadow_page_tables.install_root(spt.token(), mode);
-            self.shadow_state.shadow_page_tables.push(satp, spt);
-        }else{
-            // 如果存在的话，根据 `guest page table` 更新 `guest os SPT` 只读项
-            let guest_spt = self.shadow_state.shadow_page_tables.guest_page_table().unwrap();
-            match page_table_mode(gpt.clone(), hart_id) {
-                PageTableRoot::GVA => {
-                    // os 的内存映射几乎不会改变,因此在切换页表时不需要同步
-                    self.shadow_state.conseutive_satp_switch_count += 1;
-                    // 切换的页表为 `guest os page table`
-                    // 需要重新遍历所有页表项，并将其设置为只读
-                    collect_page_table_vpns::<P>(hart_id, satp).iter().for_each(|&vpn| {
-                        update_pte_readonly(vpn, guest_spt);
-                    });
-                },
-                PageTableRoot::UVA => {
-                    collect_page_table_vpns::<P>(hart_id, satp).iter().for_each(|&vpn| {
-                        update_pte_readonly(vpn, guest_spt);
-                    });
-                    // 需要更新用户态页表
-                    synchronize_page_table::<P>(hart_id, satp);
-                    let spt = &mut self.shadow_state.shadow_page_tables.shadow_page_table(satp).unwrap();
-                    // 为 `SPT` 映射跳板页
-                    let trampoline_hppn = hypervisor_memory.translate(VirtPageNum::from(TRAMPOLINE >> 12)).unwrap().ppn();
-                    if let Some(pte) = spt.translate(VirtPageNum::from(TRAMPOLINE >> 12)) {
-                        if !pte.is_valid() {
-                            htracking!("user remap trampoline");
-                            spt.map(VirtPageNum::from(TRAMPOLINE >> 12), trampoline_hppn, PTEFlags::R | PTEFlags::X);
-                        }
-                    }else{
-                        htracking!("user remap trampoline");
-                        spt.map(VirtPageNum::from(TRAMPOLINE >> 12), trampoline_hppn, PTEFlags::R | PTEFlags::X);
-                    }
-                        
-                    let trapctx_hvpn = VirtPageNum::from(self.translate_guest_paddr(TRAP_CONTEXT).unwrap() >> 12);
-                    let trapctx_hppn = hypervisor_memory.translate(trapctx_hvpn).unwrap().ppn();
-                    if let Some(pte) = spt.translate(VirtPageNum::from(TRAP_CONTEXT >> 12)) {
-                        if !pte.is_valid() {
-                            htracking!("user remap trap context");
-                            spt.map(VirtPageNum::from(TRAP_CONTEXT >> 12), trapctx_hppn, PTEFlags::R | PTEFlags::W);
-                        }
-                    }else{
-                        htracking!("user remap trap context");
-                        spt.map(VirtPageNum::from(TRAP_CONTEXT >> 12), trapctx_hppn, PTEFlags::R | PTEFlags::W);
+    /// 获得 `satp` 在视图 `view` 下的影子页表，不存在时根据 guest 页表新建，影子页表区域用完时回收之后重新建立
+    pub fn make_shadow_page_table(&mut self, satp: usize, view: ShadowView) -> GuestResult {
+        match self.build_shadow_page_table(satp, view) {
+            Err(GuestFault::ShadowPageTableExhausted) if self.recycle_shadow_page_tables() => self.build_shadow_page_table(satp, view),
+            result => result
+        }
+    }
+
+    /// 根据 guest 页表建立 `satp` 在视图 `view` 下的影子页表，已经存在时直接返回。
+    /// 每个视图拥有独立的影子页表，vCPU 切换特权级或者 `SUM`、`MXR` 时只需要切换根页表
+    fn build_shadow_page_table(&mut self, satp: usize, view: ShadowView) -> GuestResult {
+        if self.shadow_page_tables.shadow_page_table(satp, view).is_some() {
+            return Ok(());
+        }
+        let root_gpa = (satp & 0xfff_ffff_ffff) << 12;
+        if !is_guest_memory(root_gpa) {
+            return Err(GuestFault::InvalidPageTableAddress(root_gpa));
+        }
+        let root = self.shadow_page_tables.mirror(root_gpa, view)?;
+        self.shadow_page_tables.sync_all(satp, view)?;
+        // 无论是 guest spt 还是 user spt 都要映射跳板页与 Trap Context
+        let mut spt = P::from_ppn(root);
+        self.map_hypervisor_pages(&mut spt)?;
+        // hdebug!("Make new SPT(satp -> {:#x}, spt -> {:#x}) ", satp, spt.token());
+        self.shadow_page_tables.push(satp, view, spt);
+        self.shadow_page_tables.flush_protection();
+        Ok(())
+    }
+
+    /// 使影子页表与 guest 页表重新同步，用于模拟 `sfence.vma`。
+    /// `range` 为 `None` 或者范围过大时同步整个页表，`asid` 为 `None` 时同步所有地址空间
+    pub fn sfence_vma(&mut self, range: Option<(usize, usize)>, asid: Option<usize>) -> GuestResult {
+        let result = self.sync_shadow_page_tables(range, asid);
+        self.recycle_on_exhaustion(result, ())
+    }
+
+    fn sync_shadow_page_tables(&mut self, range: Option<(usize, usize)>, asid: Option<usize>) -> GuestResult {
+        let spts = &mut self.shadow_page_tables;
+        let roots: Vec<(usize, ShadowView)> = spts.spts().keys()
+            .copied()
+            .filter(|&(satp, _)| asid.map_or(true, |asid| (satp >> 44) & 0xffff == asid))
+            .collect();
+        for &(satp, view) in roots.iter() {
+            match range {
+                Some((start, size)) if size <= SFENCE_MAX_PAGES * PAGE_SIZE => {
+                    let end = start.saturating_add(size);
+                    for va in (start & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE) {
+                        spts.sync_entry(satp, va, view)?;
                     }
-                    self.shadow_state.shadow_page_tables.install_root(spt.token(), PageTableRoot::UVA);
-                },
-                _ => unreachable!()
+                }
+                _ => spts.sync_all(satp, view)?
             }
         }
+        // 重新同步的叶子项指向页表页时已经设置为只读，只需要保护同步过程中新发现的页表页
+        spts.flush_protection();
+        Ok(())
     }
 
+    /// 根据 guest 页表重新同步当前影子页表中 `vaddr` 所在的页表项，返回影子页表项是否过期
+    pub fn fix_shadow_page_table(&mut self, vaddr: usize) -> GuestResult<bool> {
+        let result = self.sync_stale_entry(vaddr);
+        // 回收之后 guest 重新访问时使用新建立的影子页表
+        self.recycle_on_exhaustion(result, true)
+    }
+
+    fn sync_stale_entry(&mut self, vaddr: usize) -> GuestResult<bool> {
+        let state = self.shadow_state();
+        let (satp, view) = (state.csrs.satp, ShadowView::new(state));
+        let vpn = VirtPageNum::from(vaddr >> 12);
+        let spts = &mut self.shadow_page_tables;
+        let stale = match spts.shadow_page_table(satp, view) {
+            Some(spt) => spt.translate(vpn),
+            None => return Ok(false)
+        };
+        // 重新同步的叶子项指向页表页时已经设置为只读，只需要保护同步过程中新发现的页表页
+        spts.sync_entry(satp, vaddr, view)?;
+        spts.flush_protection();
+        let fixed = spts.shadow_page_table(satp, view).and_then(|spt| spt.translate(vpn));
+        Ok(fixed != stale)
+    }
 
+    /// 返回 guest 之前根据当前 vCPU 的 satp、特权级与 `SUM`、`MXR` 准备影子页表，
+    /// 每个 vCPU 只切换自己使用的根页表，不会改变其它 vCPU 正在使用的影子页表
+    pub fn update_shadow_view(&mut self) -> GuestResult {
+        if self.shadow() != PageTableRoot::GPA {
+            let state = self.shadow_state();
+            let (satp, view) = (state.csrs.satp, ShadowView::new(state));
+            self.make_shadow_page_table(satp, view)?;
+        }
+        let generation = self.shadow_page_tables.generation;
+        self.vcpu_mut().spt_generation = generation;
+        Ok(())
+    }
 
-    pub fn synchronize_page_table(&mut self, va: usize, pte: PageTableEntry) {
-        let hart_id = self.guest_id;
-        // 获取对应影子页表的地址
-        let host_pa = gpt2spt(va, hart_id);
-        let host_ppn = PhysPageNum::from(host_pa >> 12);
-        // 获得影子页表
-        let guest_spt = self.shadow_state.shadow_page_tables.guest_page_table().unwrap();
+    /// 将 guest 内核对页表页 `va` 处的写入同步到所有视图的影子页表中(guest 内核恒等映射页表页)
+    pub fn synchronize_page_table(&mut self, va: usize, pte: PageTableEntry) -> GuestResult {
         if va % core::mem::size_of::<PageTableEntry>() != 0 {
-            panic!("Page Table Entry aligned?");
-        }else if va % core::mem::size_of::<PageTableEntry>() == 0 && pte.bits == 0 {
-            // 页表项对齐且物理页号为 0, 写入 `u8`
-            unsafe{ core::ptr::write(host_pa as *mut usize, pte.bits as usize) };
-            // 消除页表映射，将页表内存修改为可读可写
-            clear_page_table(guest_spt, va, hart_id);
-        }else {
-            // 如果页表项对齐且物理页号不为零表示进行页表映射
-            let index = (host_pa & 0xfff) / core::mem::size_of::<PageTableEntry>();
-            let pte_array = host_ppn.get_pte_array();
-            if pte.is_valid() && (pte.readable() | pte.writable() | pte.executable()) {
-                // 叶子节点
-                let new_ppn = PhysPageNum::from(gpa2hpa(pte.ppn().0 << 12, hart_id) >> 12);
-                let new_flags = pte.flags() | PTEFlags::U;
-                let new_pte = PageTableEntry::new(new_ppn, new_flags);
-                pte_array[index] = new_pte;
-                let vpn = VirtPageNum::from(va >> 12);
-                if let Some(pte) = guest_spt.translate(vpn) {
-                    if pte.writable() | pte.executable() {
-                        htracking!("Allocate page table, ppn: {:#x}", vpn.0);
-                        update_pte_readonly(vpn, guest_spt);
-                    }
-                }else{
-                    panic!()
-                }
+            return Err(GuestFault::MisalignedPageTableWrite(va));
+        }
+        let result = self.write_page_table_entry(va, pte);
+        self.recycle_on_exhaustion(result, ())
+    }
 
-            }else if pte.is_valid() && !(pte.readable() | pte.writable() | pte.executable()) {
-                // 非叶子节点
-                // 获取非叶子节点的偏移
-                let new_ppn = PhysPageNum::from(gpt2spt(pte.ppn().0 << 12, hart_id) >> 12);
-                let new_flags = pte.flags();
-                let new_pte = PageTableEntry::new(new_ppn, new_flags);
-                pte_array[index] = new_pte;
-                // 判断当前页面是否设置为只读
-                let vpn = VirtPageNum::from(va >> 12);
-                if let Some(pte) = guest_spt.translate(vpn) {
-                    if pte.writable() | pte.executable() {
-                        htracking!("Allocate page table, ppn: {:#x}", vpn.0);
-                        update_pte_readonly(vpn, guest_spt);
-                    }
-                }else{
-                    unreachable!()
-                }
-            }else{
-                unreachable!()
+    fn write_page_table_entry(&mut self, va: usize, pte: PageTableEntry) -> GuestResult {
+        let page = va & !(PAGE_SIZE - 1);
+        let index = (va & (PAGE_SIZE - 1)) / core::mem::size_of::<PageTableEntry>();
+        let spts = &mut self.shadow_page_tables;
+        spts.write_entry(page, index, pte)?;
+        if pte.bits == 0 && spts.release_page_table(page) {
+            // 消除页表映射，恢复 guest 内核对页表内存的写权限
+            let roots: Vec<(usize, ShadowView)> = spts.spts().keys()
+                .copied()
+                .filter(|(_, view)| !view.user)
+                .collect();
+            for (satp, view) in roots {
+                spts.sync_entry(satp, page, view)?;
             }
         }
+        // 新的页表页在 guest 内核影子页表中设置为只读
+        spts.flush_protection();
+        Ok(())
     }
 
 }
-
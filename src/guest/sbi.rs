@@ -1,3 +1,10 @@
+//! Guest OS 使用的 SBI 调用编号
+//!
+//! legacy 扩展只使用 `a7` 作为调用编号，`a0` 作为返回值；
+//! v0.2 之后的扩展使用 `a7` 作为扩展编号(EID)，`a6` 作为函数编号(FID)，
+//! 返回时 `a0` 为错误码，`a1` 为返回值。
+
+// legacy 扩展
 pub const SBI_SET_TIMER: usize = 0;
 pub const SBI_CONSOLE_PUTCHAR: usize = 1;
 pub const SBI_CONSOLE_GETCHAR: usize = 2;
@@ -6,4 +13,119 @@ pub const SBI_SEND_IPI: usize = 4;
 pub const SBI_REMOTE_FENCE_I: usize = 5;
 pub const SBI_REMOTE_SFENCE_VMA: usize = 6;
 pub const SBI_REMOTE_SFENCE_VMA_ASID: usize = 7;
-pub const SBI_SHUTDOWN: usize = 8;
\ No newline at end of file
+pub const SBI_SHUTDOWN: usize = 8;
+
+// 扩展编号
+pub const EID_BASE: usize = 0x10;
+pub const EID_TIME: usize = 0x5449_4D45;
+pub const EID_HSM: usize = 0x48_534D;
+pub const EID_IPI: usize = 0x73_5049;
+pub const EID_RFENCE: usize = 0x5246_4E43;
+pub const EID_SRST: usize = 0x5352_5354;
+pub const EID_DBCN: usize = 0x4442_434E;
+/// hypocaust 半虚拟化扩展，位于固件自定义的扩展编号范围(0x0A00_0000 ~ 0x0AFF_FFFF)
+pub const EID_HYPOCAUST: usize = 0x0A48_5950;
+
+// Base 扩展函数编号
+pub const BASE_GET_SPEC_VERSION: usize = 0;
+pub const BASE_GET_IMPL_ID: usize = 1;
+pub const BASE_GET_IMPL_VERSION: usize = 2;
+pub const BASE_PROBE_EXTENSION: usize = 3;
+pub const BASE_GET_MVENDORID: usize = 4;
+pub const BASE_GET_MARCHID: usize = 5;
+pub const BASE_GET_MIMPID: usize = 6;
+
+// TIME 扩展函数编号
+pub const TIME_SET_TIMER: usize = 0;
+
+// HSM 扩展函数编号
+pub const HSM_HART_START: usize = 0;
+pub const HSM_HART_STOP: usize = 1;
+pub const HSM_HART_GET_STATUS: usize = 2;
+pub const HSM_HART_SUSPEND: usize = 3;
+
+// HSM 扩展 hart 状态
+pub const HSM_STATUS_STARTED: usize = 0;
+pub const HSM_STATUS_STOPPED: usize = 1;
+pub const HSM_STATUS_START_PENDING: usize = 2;
+pub const HSM_STATUS_SUSPENDED: usize = 4;
+pub const HSM_STATUS_RESUME_PENDING: usize = 6;
+
+// HSM 扩展挂起类型
+pub const HSM_SUSPEND_RETENTIVE: usize = 0x0000_0000;
+pub const HSM_SUSPEND_NON_RETENTIVE: usize = 0x8000_0000;
+
+// IPI 扩展函数编号
+pub const IPI_SEND_IPI: usize = 0;
+
+// RFENCE 扩展函数编号
+pub const RFENCE_REMOTE_FENCE_I: usize = 0;
+pub const RFENCE_REMOTE_SFENCE_VMA: usize = 1;
+pub const RFENCE_REMOTE_SFENCE_VMA_ASID: usize = 2;
+
+// SRST 扩展函数编号
+pub const SRST_SYSTEM_RESET: usize = 0;
+
+// SRST 扩展重置类型与原因
+pub const SRST_TYPE_SHUTDOWN: usize = 0;
+pub const SRST_TYPE_COLD_REBOOT: usize = 1;
+pub const SRST_TYPE_WARM_REBOOT: usize = 2;
+pub const SRST_REASON_NONE: usize = 0;
+pub const SRST_REASON_SYSTEM_FAILURE: usize = 1;
+
+// DBCN 扩展函数编号
+pub const DBCN_CONSOLE_WRITE: usize = 0;
+pub const DBCN_CONSOLE_READ: usize = 1;
+pub const DBCN_CONSOLE_WRITE_BYTE: usize = 2;
+
+// hypocaust 扩展函数编号
+pub const HYPOCAUST_GET_GUEST_ID: usize = 0;
+pub const HYPOCAUST_GET_MEMORY_BASE: usize = 1;
+pub const HYPOCAUST_GET_MEMORY_SIZE: usize = 2;
+pub const HYPOCAUST_GET_STAT: usize = 3;
+pub const HYPOCAUST_YIELD: usize = 4;
+pub const HYPOCAUST_SET_SHARED_PAGE: usize = 5;
+pub const HYPOCAUST_GET_STOLEN_TIME: usize = 6;
+
+// hypocaust 扩展统计信息编号
+pub const HYPOCAUST_STAT_TRAPS: usize = 0;
+pub const HYPOCAUST_STAT_SBI_CALLS: usize = 1;
+pub const HYPOCAUST_STAT_EMULATED_INSTRUCTIONS: usize = 2;
+pub const HYPOCAUST_STAT_PAGE_FAULTS: usize = 3;
+pub const HYPOCAUST_STAT_TIMER_INTERRUPTS: usize = 4;
+pub const HYPOCAUST_STAT_VCPU_SWITCHES: usize = 5;
+
+// 错误码
+pub const SBI_SUCCESS: isize = 0;
+pub const SBI_ERR_FAILED: isize = -1;
+pub const SBI_ERR_NOT_SUPPORTED: isize = -2;
+pub const SBI_ERR_INVALID_PARAM: isize = -3;
+pub const SBI_ERR_DENIED: isize = -4;
+pub const SBI_ERR_INVALID_ADDRESS: isize = -5;
+pub const SBI_ERR_ALREADY_AVAILABLE: isize = -6;
+pub const SBI_ERR_ALREADY_STARTED: isize = -7;
+pub const SBI_ERR_ALREADY_STOPPED: isize = -8;
+
+/// hypervisor 向 guest 报告的 SBI 规范版本(v2.0，DBCN 扩展在 v2.0 中引入)
+pub const SBI_SPEC_VERSION: usize = 2 << 24;
+/// hypervisor 作为 SBI 实现的编号，不与已分配的编号冲突
+pub const SBI_IMPL_ID: usize = 0x4859_5043;
+/// hypervisor 作为 SBI 实现的版本(v0.1.0)
+pub const SBI_IMPL_VERSION: usize = 0x0000_0100;
+
+/// SBI 调用的返回值，写回 guest 的 `a0` 与 `a1`
+#[derive(Clone, Copy, Debug)]
+pub struct SbiRet {
+    pub error: isize,
+    pub value: usize
+}
+
+impl SbiRet {
+    pub fn ok(value: usize) -> Self {
+        Self { error: SBI_SUCCESS, value }
+    }
+
+    pub fn error(error: isize) -> Self {
+        Self { error, value: 0 }
+    }
+}
@@ -0,0 +1,162 @@
+//! Guest OS 的虚拟 CPU
+//!
+//! 每个 vCPU 拥有独立的 Trap Context、任务上下文、内核栈以及影子 CSR，
+//! 调度器以 vCPU 为单位在物理核上运行 guest。guest 通过 SBI HSM 扩展启动和停止 vCPU。
+
+use riscv::addr::BitField;
+
+use crate::constants::csr::sie::STIE;
+use crate::constants::csr::sip::SSIP_BIT;
+use crate::constants::csr::status::{STATUS_SIE_BIT, STATUS_FS, STATUS_SD};
+use crate::constants::layout::vcpu_kernel_stack_position;
+use crate::hypervisor::HYPERVISOR_MEMORY;
+use crate::hypervisor::trap::{TrapContext, trap_handler};
+use crate::page_table::PhysPageNum;
+
+use super::context::{ShadowState, TaskContext};
+use super::fpu::FpContext;
+
+/// vCPU 运行状态
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum VCpuState {
+    /// 未启动或已经停止
+    Stopped,
+    /// 可被调度运行
+    Ready,
+    /// 正在运行
+    Running,
+    /// 被挂起，直到有中断等待处理时才会被唤醒
+    Suspended,
+    /// 执行 `wfi` 等待中断，与挂起相同，但是对 guest 而言仍然处于运行状态
+    Idle
+}
+
+pub struct VCpu {
+    pub vcpu_id: usize,
+    pub trap_cx_ppn: PhysPageNum,
+    pub task_cx: TaskContext,
+    pub state: VCpuState,
+    pub shadow_state: ShadowState,
+    /// 浮点寄存器上下文
+    pub fp: FpContext,
+    /// 下一次运行时的入口地址以及 `a1` 参数，由 `prepare_run` 设置上下文
+    pub entry: Option<(usize, usize)>,
+    /// `entry` 是否为非保持挂起之后的恢复地址，否则为 `HART_START` 设置的启动地址
+    pub resuming: bool,
+    /// guest 通过 hypocaust 扩展主动让出处理器，在陷入返回前被调度器处理
+    pub yielded: bool,
+    /// 有新的中断等待处理，vCPU 在其他物理核上运行时需要通过 IPI 使其陷入，由 `Hypervisor::kick_vcpus` 处理
+    pub kick: bool,
+    /// 上一次返回 guest 时使用的影子页表所属的代数，见 `ShadowPageTables::recycle`
+    pub spt_generation: usize,
+    /// 可以运行但是没有被调度的时间(guest 时间)
+    pub stolen_time: usize,
+    /// 开始等待调度时的 guest 时间
+    pub ready_since: Option<usize>
+}
+
+impl VCpu {
+    pub fn new(vcpu_id: usize, trap_cx_ppn: PhysPageNum) -> Self {
+        Self {
+            vcpu_id,
+            trap_cx_ppn,
+            task_cx: TaskContext::zero_init(),
+            state: VCpuState::Stopped,
+            shadow_state: ShadowState::new(),
+            fp: FpContext::new(),
+            entry: None,
+            resuming: false,
+            yielded: false,
+            kick: false,
+            spt_generation: 0,
+            stolen_time: 0,
+            ready_since: None
+        }
+    }
+
+    pub fn trap_cx(&self) -> &'static mut TrapContext {
+        self.trap_cx_ppn.get_mut()
+    }
+
+    /// 是否可以被调度运行
+    pub fn runnable(&self) -> bool {
+        self.state == VCpuState::Ready || self.state == VCpuState::Running
+    }
+
+    /// 是否在等待中断(被挂起或者执行了 `wfi`)
+    pub fn waiting(&self) -> bool {
+        self.state == VCpuState::Suspended || self.state == VCpuState::Idle
+    }
+
+    /// 在 guest 时间 `now` 是否有使能的中断等待处理(用于唤醒挂起的 vCPU)，
+    /// 虚拟时钟到期只有在 guest 使能了时钟中断时才会唤醒 vCPU
+    pub fn interrupt_pending(&self, now: usize) -> bool {
+        let csrs = &self.shadow_state.csrs;
+        let timer = csrs.sie & STIE != 0 && self.shadow_state.timer_deadline() <= now;
+        csrs.sie & csrs.sip != 0 || timer
+    }
+
+    /// 向 vCPU 发送软件中断，挂起的 vCPU 在中断使能时被唤醒
+    pub fn send_ipi(&mut self) {
+        self.shadow_state.csrs.sip.set_bit(SSIP_BIT, true);
+        self.shadow_state.interrupt = true;
+        self.kick = true;
+        if self.waiting() && self.shadow_state.csrs.sie & self.shadow_state.csrs.sip != 0 {
+            self.state = VCpuState::Ready;
+        }
+    }
+
+    /// 停止 vCPU 并清除影子状态
+    pub fn stop(&mut self) {
+        self.state = VCpuState::Stopped;
+        self.shadow_state = ShadowState::new();
+        self.fp = FpContext::new();
+        self.entry = None;
+        self.resuming = false;
+        self.yielded = false;
+        self.kick = false;
+        self.stolen_time = 0;
+        self.ready_since = None;
+    }
+
+    /// 启动 vCPU，从 `entry` 开始运行，`a0` 为 hart id，`a1` 为 `opaque`
+    pub fn start(&mut self, entry: usize, opaque: usize) {
+        self.entry = Some((entry, opaque));
+        self.resuming = false;
+        self.state = VCpuState::Ready;
+    }
+
+    /// 非保持挂起，唤醒后从 `resume_addr` 开始运行，`a1` 为 `opaque`
+    pub fn suspend_non_retentive(&mut self, resume_addr: usize, opaque: usize) {
+        self.entry = Some((resume_addr, opaque));
+        self.resuming = true;
+    }
+
+    /// 运行之前根据入口地址初始化 vCPU 的中断上下文与任务上下文，
+    /// 必须在 vCPU 的上下文没有被任何物理核使用时调用
+    pub fn prepare_run(&mut self, guest_id: usize) {
+        if let Some((entry, opaque)) = self.entry.take() {
+            self.resuming = false;
+            let (_, kernel_stack_top) = vcpu_kernel_stack_position(guest_id, self.vcpu_id);
+            self.task_cx = TaskContext::goto_trap_return(kernel_stack_top);
+            // 设置 Guest OS `sstatus` 的 `SPP`，并关闭中断、浮点单元与分页
+            let mut sstatus = riscv::register::sstatus::read();
+            sstatus.set_spp(riscv::register::sstatus::SPP::Supervisor);
+            let csrs = &mut self.shadow_state.csrs;
+            csrs.sstatus = sstatus.bits() & !(STATUS_FS | STATUS_SD);
+            csrs.sstatus.set_bit(STATUS_SIE_BIT, false);
+            csrs.satp = 0;
+            self.shadow_state.supervisor = true;
+            let trap_cx = self.trap_cx();
+            *trap_cx = TrapContext::app_init_context(
+                entry,
+                0,
+                HYPERVISOR_MEMORY.exclusive_access().token(),
+                kernel_stack_top,
+                trap_handler as usize,
+            );
+            trap_cx.x[10] = self.vcpu_id;
+            trap_cx.x[11] = opaque;
+        }
+    }
+}
@@ -6,13 +6,21 @@ use fdt::Fdt;
 #[derive(Clone, Debug)]
 pub struct Device {
     pub base_address: usize,
-    pub size: usize
+    pub size: usize,
+    /// 设备在 PLIC 上的中断源编号
+    pub irq: Option<usize>
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct MachineMeta{
     pub physical_memory_offset: usize,
     pub physical_memory_size: usize,
+    /// 物理核数量
+    pub hart_count: usize,
+    /// 物理 PLIC
+    pub plic: Option<Device>,
+    /// 所有 guest 退出之后保持运行而不关闭机器，由 `/chosen` 的 `bootargs` 中的 `hypocaust.keep_alive` 开启
+    pub keep_alive: bool,
 
     pub virtio: ArrayVec<Device, 16>
 }
@@ -26,20 +34,34 @@ impl MachineMeta {
             meta.physical_memory_offset = region.starting_address as usize;
             meta.physical_memory_size = region.size.unwrap();
         }
+        meta.hart_count = fdt.cpus().count();
+        // 启动参数
+        if let Some(bootargs) = fdt.find_node("/chosen").and_then(|node| node.property("bootargs")).and_then(|prop| prop.as_str()) {
+            hdebug!("bootargs: {}", bootargs);
+            meta.keep_alive = bootargs.split_whitespace().any(|arg| arg == "hypocaust.keep_alive");
+        }
+        // 发现 PLIC
+        if let Some(reg) = fdt.find_node("/soc/plic").and_then(|node| node.reg()).and_then(|mut reg| reg.next()) {
+            let paddr = reg.starting_address as usize;
+            let size = reg.size.unwrap();
+            hdebug!("plic addr: {:#x}, size: {:#x}", paddr, size);
+            meta.plic = Some(Device { base_address: paddr, size, irq: None });
+        }
         // 发现 virtio mmio 设备
         for node in fdt.find_all_nodes("/soc/virtio_mmio") {
             if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
                 let paddr = reg.starting_address as usize;
                 let size = reg.size.unwrap();
+                let irq = node.interrupts().and_then(|mut irqs| irqs.next());
                 let vaddr = paddr;
                 unsafe{
                     let header = vaddr as *const u32;
                     let device_id_addr = header.add(2);
                     let device_id = core::ptr::read_volatile(device_id_addr);
                     if device_id != 0 {
-                        hdebug!("virtio mmio addr: {:#x}, size: {:#x}", paddr, size);
+                        hdebug!("virtio mmio addr: {:#x}, size: {:#x}, irq: {:?}", paddr, size, irq);
                         meta.virtio.push(
-                            Device { base_address: paddr, size }
+                            Device { base_address: paddr, size, irq }
                         )
                     }
                 }
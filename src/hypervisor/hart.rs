@@ -0,0 +1,54 @@
+//! 多核支持
+//!
+//! 主核(hart 0)完成初始化后通过 SBI HSM 扩展启动其余的核，每个核进入各自的调度循环，
+//! 并从就绪的 vCPU 中选择一个运行。hypervisor 运行时 `tp` 寄存器保存当前核的 hart id，
+//! guest 的 `tp` 在陷入与返回时由 `trap.S` 保存和恢复。
+
+use crate::constants::layout::MAX_HARTS;
+use crate::guest::context::TaskContext;
+use crate::sbi::hart_start;
+
+/// 每个物理核的运行状态
+pub struct HartState {
+    /// 当前核上正在运行的 vCPU，(guest id, vcpu id)
+    pub current: Option<(usize, usize)>,
+    /// 刚被切换出去、等待设置为就绪的 vCPU
+    pub prev: Option<(usize, usize)>,
+    /// 当前核的浮点寄存器属于哪个 vCPU，(guest id, vcpu id)
+    pub fp_owner: Option<(usize, usize)>,
+    /// 调度循环的任务上下文，没有 vCPU 可以运行时回到这里
+    pub idle_task_cx: TaskContext
+}
+
+impl HartState {
+    pub fn new() -> Self {
+        Self {
+            current: None,
+            prev: None,
+            fp_owner: None,
+            idle_task_cx: TaskContext::zero_init()
+        }
+    }
+}
+
+/// 获取当前核的 hart id
+#[inline(always)]
+pub fn hart_id() -> usize {
+    let hart_id;
+    unsafe{ core::arch::asm!("mv {}, tp", out(reg) hart_id) };
+    hart_id
+}
+
+/// 通过 SBI HSM 扩展启动除当前核之外的其他核，从核同样从 `_start` 进入
+pub fn start_secondary_harts(hart_count: usize, device_tree_blob: usize) {
+    extern "C" {
+        fn _start();
+    }
+    for hart in 0..hart_count.min(MAX_HARTS) {
+        if hart == hart_id() { continue; }
+        let error = hart_start(hart, _start as usize, device_tree_blob);
+        if error != 0 {
+            hwarning!("failed to start hart {}, error: {:#x}", hart, error);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! 物理外部中断的路由
+//!
+//! hypervisor 在每个核的 S 态上下文中认领物理 PLIC 的中断，将其注入到拥有该设备的 guest 的虚拟 PLIC 中，
+//! 物理中断源在 guest complete 之前保持被认领的状态，guest complete 之后再在 host 上 complete。
+
+use crate::debug::PageDebug;
+use crate::device_emu::{HostPlic, PLIC_SOURCES, is_device_access};
+use crate::page_table::PageTable;
+use crate::constants::layout::MAX_HARTS;
+
+use super::{Hypervisor, MachineMeta, hart_id};
+
+/// 物理中断源的所有者以及转发状态
+pub struct IrqRouter {
+    pub plic: Option<HostPlic>,
+    /// 拥有中断源的 guest id
+    owner: [Option<usize>; PLIC_SOURCES],
+    /// 已经注入 guest 但是 guest 尚未 complete 的中断源
+    forwarded: [bool; PLIC_SOURCES],
+    /// 使能中断源的核数量
+    hart_count: usize
+}
+
+impl IrqRouter {
+    pub fn new(meta: &MachineMeta) -> Self {
+        let hart_count = meta.hart_count.min(MAX_HARTS);
+        let plic = meta.plic.as_ref().map(|plic| {
+            let mut plic = HostPlic::new(plic.base_address, plic.size);
+            for hart in 0..hart_count {
+                plic.set_threshold(hart, 0);
+            }
+            plic
+        });
+        Self {
+            plic,
+            owner: [None; PLIC_SOURCES],
+            forwarded: [false; PLIC_SOURCES],
+            hart_count
+        }
+    }
+
+    /// 将中断源分配给 guest，并在所有核上使能该中断源
+    fn assign(&mut self, irq: usize, guest_id: usize) {
+        let plic = match self.plic.as_mut() {
+            Some(plic) if irq != 0 && irq < PLIC_SOURCES && self.owner[irq].is_none() => plic,
+            _ => return
+        };
+        hdebug!("assign irq {} to guest kernel {}", irq, guest_id);
+        self.owner[irq] = Some(guest_id);
+        plic.set_priority(irq, 1);
+        for hart in 0..self.hart_count {
+            plic.set_enable(hart, irq, true);
+        }
+    }
+
+    /// 在 host 上 complete 已经转发给 guest 的中断源
+    fn complete(&mut self, irq: usize) {
+        if core::mem::take(&mut self.forwarded[irq]) {
+            if let Some(plic) = self.plic.as_mut() {
+                plic.complete(hart_id(), irq);
+            }
+        }
+    }
+}
+
+impl<P: PageTable + PageDebug> Hypervisor<P> {
+    /// 将直通给 guest 的设备的中断源分配给 guest，已经被其他 guest 拥有的中断源不会重新分配
+    pub fn assign_interrupts(&mut self, guest_id: usize) {
+        let irqs = self.meta.virtio.iter()
+            .filter(|device| is_device_access(device.base_address))
+            .filter_map(|device| device.irq);
+        for irq in irqs {
+            self.irqs.assign(irq, guest_id);
+        }
+    }
+
+    /// guest 被重置或销毁时 complete 其尚未 complete 的中断源，`release` 为真时同时释放中断源
+    pub fn release_interrupts(&mut self, guest_id: usize, release: bool) {
+        for irq in 1..PLIC_SOURCES {
+            if self.irqs.owner[irq] != Some(guest_id) { continue; }
+            self.irqs.complete(irq);
+            if release {
+                self.irqs.owner[irq] = None;
+                if let Some(plic) = self.irqs.plic.as_mut() {
+                    plic.set_priority(irq, 0);
+                    for hart in 0..self.irqs.hart_count {
+                        plic.set_enable(hart, irq, false);
+                    }
+                }
+            }
+        }
+    }
+
+    /// 认领当前核上所有挂起的外部中断，并注入到拥有该中断源的 guest 的虚拟 PLIC 中
+    pub fn handle_external_interrupt(&mut self) {
+        let hart = hart_id();
+        let router = &mut self.irqs;
+        let plic = match router.plic.as_mut() {
+            Some(plic) => plic,
+            None => return
+        };
+        loop {
+            let irq = plic.claim(hart);
+            if irq == 0 { break; }
+            match router.owner.get(irq).copied().flatten().and_then(|guest_id| self.guests[guest_id].as_mut()) {
+                Some(guest) => {
+                    htracking!("forward irq {} to guest kernel {}", irq, guest.guest_id);
+                    router.forwarded[irq] = true;
+                    guest.virt_device.plic.set_pending(irq, true);
+                    guest.update_external_interrupts();
+                }
+                None => {
+                    hwarning!("external interrupt {} has no owner", irq);
+                    plic.complete(hart, irq);
+                }
+            }
+        }
+    }
+
+    /// 当前 guest complete 虚拟中断源之后在 host 上 complete 对应的物理中断源
+    pub fn complete_external_interrupts(&mut self) {
+        let guest_id = self.current_guest_id();
+        let guest = match self.guests[guest_id].as_mut() {
+            Some(guest) => guest,
+            None => return
+        };
+        while let Some(irq) = guest.virt_device.plic.take_completed() {
+            if self.irqs.owner[irq] == Some(guest_id) {
+                self.irqs.complete(irq);
+            }
+        }
+    }
+}
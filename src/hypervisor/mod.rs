@@ -2,16 +2,22 @@ use alloc::vec::Vec;
 use spin::Mutex;
 
 
-use crate::constants::layout::TRAP_CONTEXT;
-use crate::guest::GuestKernel;
-use crate::page_table::{PageTable, PageTableSv39, VirtPageNum};
+use crate::constants::layout::{MAX_GUESTS, MAX_HARTS, MAX_VCPUS, vcpu_kernel_stack_position};
+use crate::guest::{GuestKernel, GuestState, GuestConfig, VCpuState};
+use crate::mm::MemorySet;
+use crate::page_table::{PageTable, PageTableSv39, VirtAddr};
 use crate::debug::PageDebug;
 use crate::guest::context::TaskContext;
 use crate::guest::switch::__switch;
+use crate::timer;
+use crate::constants::csr::status::{STATUS_FS, FS_OFF, FS_CLEAN};
+use crate::sbi::{poweroff, send_ipi};
 
 pub use self::hyp_alloc::FrameTracker;
 pub use self::fdt::MachineMeta;
 pub use self::shared::HYPERVISOR_MEMORY;
+pub use self::hart::{HartState, hart_id};
+use self::irq::IrqRouter;
 use self::trap::TrapContext;
 
 
@@ -20,62 +26,450 @@ pub mod hyp_alloc;
 pub mod trap;
 pub mod fdt;
 pub mod shared;
+pub mod hart;
+pub mod irq;
 
 pub struct Hypervisor<P: PageTable + PageDebug> {
     pub meta: MachineMeta,
-    pub guests: Vec<GuestKernel<P>>,
-    pub guest_run_id: usize
+    /// guest 槽位，下标即为 guest id
+    pub guests: Vec<Option<GuestKernel<P>>>,
+    /// 每个物理核的运行状态，下标即为 hart id
+    pub harts: Vec<HartState>,
+    /// 物理外部中断的路由
+    pub irqs: IrqRouter,
+    /// 所有 guest 都关机或崩溃之后是否关闭机器，启动参数中包含 `hypocaust.keep_alive` 时保持运行
+    pub poweroff_on_exit: bool
 }
 
 
 pub static HYPOCAUST: Mutex<Option<Hypervisor<PageTableSv39>>> = Mutex::new(None);
 
 impl<P: PageTable + PageDebug> Hypervisor<P> {
-    pub fn create_guest() {
+    /// 创建 guest kernel 并返回其 guest id，没有空闲槽位时返回 `None`
+    /// 
+    /// guest id 决定了 guest 使用的物理内存段、影子页表区域以及内核栈
+    pub fn create_guest(&mut self, image: &'static [u8], config: GuestConfig) -> Option<usize> {
+        let guest_id = self.guests.iter().position(|guest| guest.is_none())?;
+        hdebug!("create guest kernel {}......", guest_id);
+        // 为 hypervisor 映射 guest 物理内存段与影子页表区域
+        HYPERVISOR_MEMORY.exclusive_access().map_guest_segment(guest_id);
+        // 将 guest kernel 加载到对应的物理内存段
+        let guest_kernel_memory = MemorySet::new_guest_kernel(image, guest_id);
+        // 创建用户态的 guest kernel 内存空间
+        let user_guest_kernel_memory = MemorySet::create_user_guest_kernel(&guest_kernel_memory);
+        let guest = GuestKernel::new(user_guest_kernel_memory, guest_id, image, config);
+        self.guests[guest_id] = Some(guest);
+        self.assign_interrupts(guest_id);
+        Some(guest_id)
+    }
+
+    /// guest 的 vCPU 是否正在某个核上运行(或者上下文尚未保存完毕)
+    fn on_hart(&self, guest_id: usize) -> bool {
+        self.harts.iter().any(|hart| {
+            matches!(hart.current, Some((id, _)) if id == guest_id) || matches!(hart.prev, Some((id, _)) if id == guest_id)
+        })
+    }
+
+    /// vCPU 是否正在某个核上运行(或者上下文尚未保存完毕)
+    fn vcpu_on_hart(&self, guest_id: usize, vcpu_id: usize) -> bool {
+        self.harts.iter().any(|hart| hart.current == Some((guest_id, vcpu_id)) || hart.prev == Some((guest_id, vcpu_id)))
+    }
+
+    /// 暂停 guest，正在运行的 vCPU 会在下一次陷入时让出处理器
+    pub fn pause_guest(&mut self, guest_id: usize) -> bool {
+        match self.guests[guest_id].as_mut() {
+            Some(guest) if guest.runnable() => {
+                guest.state = GuestState::Paused;
+                guest.clock.pause();
+                true
+            }
+            _ => false
+        }
+    }
+
+    /// 恢复被暂停的 guest
+    pub fn resume_guest(&mut self, guest_id: usize) -> bool {
+        match self.guests[guest_id].as_mut() {
+            Some(guest) if guest.state == GuestState::Paused => {
+                guest.state = GuestState::Ready;
+                guest.clock.resume();
+                true
+            }
+            _ => false
+        }
+    }
+
+    /// 停止 guest 并将其设置为 `state`，guest 会在下一次调度时让出处理器，
+    /// 停止的 guest 不会再 complete 已经转发的中断源，因此同时释放其拥有的中断源
+    pub fn stop_guest(&mut self, guest_id: usize, state: GuestState) -> bool {
+        assert!(state == GuestState::Halted || state == GuestState::Crashed);
+        match self.guests[guest_id].as_mut() {
+            Some(guest) => {
+                guest.virt_device.console.flush_partial();
+                guest.state = state;
+            }
+            None => return false
+        }
+        self.release_interrupts(guest_id, true);
+        true
+    }
 
+    /// 重置 guest：重新加载镜像并清除影子状态，之后 vCPU 0 从入口地址重新运行。
+    /// 只能重置没有运行的 guest 或者只有当前核在运行的 guest
+    pub fn reset_guest(&mut self, guest_id: usize) -> bool {
+        let current = self.harts[hart_id()].current;
+        let on_this_hart = matches!(current, Some((id, _)) if id == guest_id);
+        let on_other_harts = self.harts.iter().enumerate().any(|(hart, state)| {
+            hart != hart_id() && (matches!(state.current, Some((id, _)) if id == guest_id) || matches!(state.prev, Some((id, _)) if id == guest_id))
+        });
+        if on_other_harts {
+            return false;
+        }
+        // 虚拟 PLIC 会被重置，guest 不会再 complete 已经转发的中断源
+        self.release_interrupts(guest_id, false);
+        let guest = match self.guests[guest_id].as_mut() {
+            Some(guest) => guest,
+            None => return false
+        };
+        hdebug!("reset guest kernel {}......", guest_id);
+        guest.reset();
+        guest.state = if on_this_hart { GuestState::Running } else { GuestState::Ready };
+        // 当前核上的 vCPU 0 在陷入返回前从入口地址继续运行，避免被其他核同时调度
+        if let Some((_, vcpu_id)) = current.filter(|_| on_this_hart) {
+            let vcpu = &mut guest.vcpus[vcpu_id];
+            if vcpu.state == VCpuState::Ready {
+                vcpu.state = VCpuState::Running;
+            }
+        }
+        // 停止时释放的中断源重新分配给 guest
+        self.assign_interrupts(guest_id);
+        true
     }
-    pub fn run_guest(&self, guest_id: usize) -> ! {
-        let guest_kernel = &self.guests[guest_id];
-        let task_cx_ptr = &guest_kernel.task_cx as *const TaskContext;
-        let mut _unused = TaskContext::zero_init();
-        hdebug!("run guest kernel {}......", guest_id);
-        // before this, we should drop local variables that must be dropped manually
-        unsafe {
-            __switch(&mut _unused as *mut _, task_cx_ptr);
+
+    /// 销毁没有在运行的 guest，释放其内存空间、影子页表以及内核栈
+    pub fn destroy_guest(&mut self, guest_id: usize) -> bool {
+        if self.on_hart(guest_id) {
+            return false;
+        }
+        self.release_interrupts(guest_id, true);
+        if let Some(guest) = self.guests[guest_id].take() {
+            hdebug!("destroy guest kernel {}......", guest_id);
+            // 释放 guest 内存空间以及影子页表占用的页帧
+            drop(guest);
+            let mut hypervisor_memory = HYPERVISOR_MEMORY.exclusive_access();
+            // 释放所有 vCPU 的内核栈
+            for vcpu_id in 0..MAX_VCPUS {
+                let (kernel_stack_bottom, _) = vcpu_kernel_stack_position(guest_id, vcpu_id);
+                hypervisor_memory.remove_area_with_start_vpn(VirtAddr::from(kernel_stack_bottom).floor());
+            }
+            hypervisor_memory.unmap_guest_segment(guest_id);
+            true
+        }else{
+            false
         }
-        panic!("unreachable in run_first_task!");
     }
 
-    pub fn add_guest(&mut self, guest: GuestKernel<P>) {
-        self.guests.push(guest);
+    /// 当前核上正在运行的 guest id
+    pub fn current_guest_id(&self) -> usize {
+        self.harts[hart_id()].current.unwrap().0
     }
 
-    pub fn current_user_token(&self) -> usize {
-        let guest = &self.guests[self.guest_run_id];
-        guest.get_user_token()
+    /// 当前核上正在运行的 vCPU id
+    pub fn current_vcpu_id(&self) -> usize {
+        self.harts[hart_id()].current.unwrap().1
+    }
+
+    pub fn current_user_token(&mut self) -> usize {
+        self.current_guest().get_user_token()
     }
 
     pub fn current_trap_cx(&mut self) -> &'static mut TrapContext {
-        let guest = &mut self.guests[self.guest_run_id];
-        guest.memory_set.translate(VirtPageNum::from(TRAP_CONTEXT >> 12)).unwrap().ppn().get_mut()
+        self.current_guest().vcpu().trap_cx()
     }
 
+    /// 当前核上正在运行的 guest，并将其当前 vCPU 设置为本核上的 vCPU
     pub fn current_guest(&mut self) -> &mut GuestKernel<P> {
-        &mut self.guests[self.guest_run_id]
+        let (guest_id, vcpu_id) = self.harts[hart_id()].current.unwrap();
+        let guest = self.guests[guest_id].as_mut().unwrap();
+        guest.active_vcpu = vcpu_id;
+        guest
+    }
+
+    /// 上一个 vCPU 的上下文已经在 `__switch` 中保存完毕，此时才能将其设置为就绪
+    pub fn finish_switch(&mut self) {
+        if let Some((guest_id, vcpu_id)) = self.harts[hart_id()].prev.take() {
+            if let Some(guest) = self.guests[guest_id].as_mut() {
+                let now = guest.clock.now();
+                let vcpu = &mut guest.vcpus[vcpu_id];
+                if vcpu.state == VCpuState::Running {
+                    // 被抢占的 vCPU 从现在开始累计被窃取的时间
+                    vcpu.state = VCpuState::Ready;
+                    vcpu.ready_since = Some(now);
+                }
+            }
+        }
+    }
+
+    /// 根据当前 vCPU 的浮点状态设置返回 guest 时的 `sstatus.FS`，
+    /// 本次运行期间使用过浮点单元时恢复浮点寄存器(当前核上的寄存器已经属于该 vCPU 时无需恢复)
+    pub fn prepare_fp(&mut self) {
+        let hart = hart_id();
+        let (guest_id, vcpu_id) = self.harts[hart].current.unwrap();
+        let vcpu = &mut self.guests[guest_id].as_mut().unwrap().vcpus[vcpu_id];
+        let enabled = vcpu.shadow_state.csrs.sstatus & STATUS_FS != FS_OFF;
+        let fs = if vcpu.fp.active && enabled {
+            if self.harts[hart].fp_owner != Some((guest_id, vcpu_id)) || vcpu.fp.loaded_hart != Some(hart) {
+                vcpu.fp.restore(hart);
+                self.harts[hart].fp_owner = Some((guest_id, vcpu_id));
+            }
+            // 设置为 Clean，guest 修改浮点寄存器后硬件将其设置为 Dirty
+            FS_CLEAN
+        }else{
+            FS_OFF
+        };
+        vcpu.trap_cx().set_fs(fs);
+    }
+
+    /// 重置所有请求重启并且已经让出处理器的 guest
+    fn reboot_guests(&mut self) {
+        for guest_id in 0..self.guests.len() {
+            let rebooting = matches!(&self.guests[guest_id], Some(guest) if guest.state == GuestState::Rebooting);
+            if rebooting && !self.on_hart(guest_id) {
+                self.reset_guest(guest_id);
+            }
+        }
+    }
+
+    /// 所有 guest 是否都已经关机、崩溃或者被销毁
+    fn all_guests_exited(&self) -> bool {
+        self.guests.iter().all(|guest| match guest {
+            Some(guest) => guest.state == GuestState::Halted || guest.state == GuestState::Crashed,
+            None => true
+        })
+    }
+
+    /// 轮询所有 guest 的串口输入并更新外部中断，唤醒有中断等待处理的挂起 vCPU，
+    /// 所有 vCPU 都在等待中断时串口输入只能在这里产生中断
+    fn wake_suspended_vcpus(&mut self) {
+        for guest in self.guests.iter_mut().flatten().filter(|guest| guest.runnable()) {
+            guest.virt_device.uart.poll_input(timer::get_time());
+            guest.update_external_interrupts();
+        }
+        for guest in self.guests.iter_mut().flatten() {
+            let now = guest.clock.now();
+            guest.vcpus.iter_mut()
+                .filter(|vcpu| vcpu.waiting() && vcpu.interrupt_pending(now))
+                .for_each(|vcpu| {
+                    vcpu.state = VCpuState::Ready;
+                    vcpu.ready_since = Some(now);
+                });
+        }
+    }
+
+    /// 通知有新的中断等待处理的 vCPU：正在其他核上运行的 vCPU 通过 IPI 使其陷入并注入中断，
+    /// 没有在运行的 vCPU 可能被唤醒，通过 IPI 使空闲的核重新调度
+    pub fn kick_vcpus(&mut self) {
+        let current = hart_id();
+        let mut wake_idle = false;
+        for (guest_id, guest) in self.guests.iter_mut().enumerate() {
+            let guest = match guest {
+                Some(guest) => guest,
+                None => continue
+            };
+            for vcpu in guest.vcpus.iter_mut().filter(|vcpu| vcpu.kick) {
+                vcpu.kick = false;
+                match self.harts.iter().position(|hart| hart.current == Some((guest_id, vcpu.vcpu_id))) {
+                    Some(hart) if hart != current => { send_ipi(hart); }
+                    Some(_) => {}
+                    None => wake_idle = true
+                }
+            }
+        }
+        if wake_idle {
+            let hart_count = self.meta.hart_count.min(MAX_HARTS);
+            (0..hart_count)
+                .filter(|&hart| hart != current && self.harts[hart].current.is_none())
+                .for_each(|hart| { send_ipi(hart); });
+        }
+    }
+
+    /// 等待中断的 vCPU 中最早到期的虚拟时钟与下一次轮询串口输入的时间(host 时间)，用于在空闲时设置物理时钟
+    fn next_wakeup(&self) -> usize {
+        let timer = self.guests.iter().flatten()
+            .flat_map(|guest| guest.vcpus.iter()
+                .filter(|vcpu| vcpu.waiting())
+                .map(move |vcpu| guest.clock.to_host(vcpu.shadow_state.timer_deadline())))
+            .min()
+            .unwrap_or(usize::MAX);
+        let poll = self.guests.iter().flatten()
+            .filter(|guest| guest.runnable())
+            .filter_map(|guest| guest.virt_device.uart.next_poll())
+            .min()
+            .unwrap_or(usize::MAX);
+        timer.min(poll)
+    }
+
+    /// 从当前核上 vCPU 的下一个开始轮询，找到下一个可运行的 vCPU，
+    /// 正在其他核上运行或者上下文尚未保存完毕的 vCPU 不会被选中
+    pub fn find_next_vcpu(&self) -> Option<(usize, usize)> {
+        let count = self.guests.len() * MAX_VCPUS;
+        let current = self.harts[hart_id()].current
+            .map(|(guest_id, vcpu_id)| guest_id * MAX_VCPUS + vcpu_id)
+            .unwrap_or(count - 1);
+        (1..=count)
+            .map(|offset| (current + offset) % count)
+            .map(|slot| (slot / MAX_VCPUS, slot % MAX_VCPUS))
+            .find(|&(guest_id, vcpu_id)| match &self.guests[guest_id] {
+                Some(guest) => guest.runnable() && guest.vcpus[vcpu_id].state == VCpuState::Ready && !self.vcpu_on_hart(guest_id, vcpu_id),
+                None => false
+            })
+    }
+
+    /// 将 vCPU 设置为当前核上运行的 vCPU，并返回其任务上下文
+    fn run_vcpu(&mut self, guest_id: usize, vcpu_id: usize) -> *const TaskContext {
+        let hart = hart_id();
+        let guest = self.guests[guest_id].as_mut().unwrap();
+        guest.state = GuestState::Running;
+        guest.stats.vcpu_switches += 1;
+        let now = guest.clock.now();
+        let vcpu = &mut guest.vcpus[vcpu_id];
+        if let Some(ready_since) = vcpu.ready_since.take() {
+            vcpu.stolen_time += now.saturating_sub(ready_since);
+        }
+        vcpu.prepare_run(guest_id);
+        vcpu.state = VCpuState::Running;
+        // 物理时钟需要考虑即将运行的 vCPU 的虚拟时钟
+        timer::set_next_trigger(guest.clock.to_host(vcpu.shadow_state.timer_deadline()));
+        guest.update_shared_page();
+        self.harts[hart].current = Some((guest_id, vcpu_id));
+        &guest.vcpus[vcpu_id].task_cx as *const TaskContext
+    }
+}
+
+/// 每个核的调度循环，从就绪的 vCPU 中选择一个运行，没有可运行的 vCPU 时等待中断
+pub fn run_guests() -> ! {
+    loop {
+        let mut inner = HYPOCAUST.lock();
+        let hypervisor = inner.as_mut().unwrap();
+        hypervisor.finish_switch();
+        hypervisor.reboot_guests();
+        // 空闲时 `wfi` 因为外部中断返回，此时不会陷入，需要在这里认领
+        hypervisor.handle_external_interrupt();
+        hypervisor.wake_suspended_vcpus();
+        hypervisor.kick_vcpus();
+        if let Some((guest_id, vcpu_id)) = hypervisor.find_next_vcpu() {
+            let hart = hart_id();
+            let next_task_cx_ptr = hypervisor.run_vcpu(guest_id, vcpu_id);
+            let idle_task_cx_ptr = &mut hypervisor.harts[hart].idle_task_cx as *mut TaskContext;
+            drop(inner);
+            unsafe {
+                __switch(idle_task_cx_ptr, next_task_cx_ptr);
+            }
+        }else if hypervisor.poweroff_on_exit && hypervisor.all_guests_exited() {
+            hdebug!("all guest kernels exited, power off");
+            poweroff();
+        }else{
+            // 没有可运行的 vCPU，等待调度时钟或者等待中断的 vCPU 的虚拟时钟到期后重新检查
+            timer::set_next_trigger(hypervisor.next_wakeup());
+            drop(inner);
+            unsafe{ core::arch::asm!("wfi") };
+            // 其他核通过 IPI 唤醒空闲的核，软件中断不会陷入，需要在这里清除
+            unsafe{ riscv::register::sip::clear_ssoft() };
+            timer::set_default_next_trigger();
+        }
+    }
+}
+
+/// 时间片用完或当前 vCPU 不再可以运行时，保存当前 vCPU 的上下文并切换到下一个可运行的 vCPU，
+/// 没有其他可运行的 vCPU 时：当前 vCPU 仍可运行则直接返回，否则回到调度循环
+pub fn schedule() {
+    let mut inner = HYPOCAUST.lock();
+    let hypervisor = inner.as_mut().unwrap();
+    let hart = hart_id();
+    let (guest_id, vcpu_id) = hypervisor.harts[hart].current.unwrap();
+    hypervisor.reboot_guests();
+    hypervisor.wake_suspended_vcpus();
+    hypervisor.kick_vcpus();
+    let next_task_cx_ptr = match hypervisor.find_next_vcpu() {
+        Some((next_guest_id, next_vcpu_id)) => hypervisor.run_vcpu(next_guest_id, next_vcpu_id),
+        None => {
+            let guest = hypervisor.guests[guest_id].as_mut().unwrap();
+            if guest.runnable() && guest.vcpus[vcpu_id].runnable() {
+                // 当前 vCPU 可能刚被唤醒，继续运行
+                let vcpu = &mut guest.vcpus[vcpu_id];
+                vcpu.prepare_run(guest_id);
+                vcpu.state = VCpuState::Running;
+                return;
+            }
+            timer::set_next_trigger(hypervisor.next_wakeup());
+            hypervisor.harts[hart].current = None;
+            &hypervisor.harts[hart].idle_task_cx as *const TaskContext
+        }
+    };
+    let current_vcpu = &mut hypervisor.guests[guest_id].as_mut().unwrap().vcpus[vcpu_id];
+    // 浮点寄存器在换出时保存，之后可能在其他核上恢复
+    current_vcpu.fp.switch_out();
+    let current_task_cx_ptr = &mut current_vcpu.task_cx as *mut TaskContext;
+    // 当前 vCPU 的上下文保存之后才能被其他核调度，由 `finish_switch` 设置为就绪
+    hypervisor.harts[hart].prev = Some((guest_id, vcpu_id));
+    // 切换前必须释放锁，下一个 vCPU 会在 `trap_return` 中重新获取
+    drop(inner);
+    unsafe {
+        __switch(current_task_cx_ptr, next_task_cx_ptr);
     }
 }
 
 
 
 pub fn initialize_vmm(meta: MachineMeta) {
+    // hypervisor 直接驱动物理 PLIC
+    if let Some(plic) = meta.plic.as_ref() {
+        HYPERVISOR_MEMORY.exclusive_access().map_mmio(plic.base_address, plic.size);
+    }
     unsafe{ HYPOCAUST.force_unlock(); }
     let old = HYPOCAUST.lock().replace(
         Hypervisor{
+            irqs: IrqRouter::new(&meta),
+            poweroff_on_exit: !meta.keep_alive,
             meta,
-            guests: Vec::new(),
-            guest_run_id: 0
+            guests: (0..MAX_GUESTS).map(|_| None).collect(),
+            harts: (0..MAX_HARTS).map(|_| HartState::new()).collect()
         }
     );
     core::mem::forget(old);
 }
 
+
+/// 在空闲的 guest 槽位中创建 guest 并依次暂停、恢复、停止、重置以及销毁，需要在启动其他核之前调用
+#[allow(unused)]
+pub fn guest_lifecycle_test(image: &'static [u8]) {
+    let mut inner = HYPOCAUST.lock();
+    let hypervisor = inner.as_mut().unwrap();
+    let guest_id = hypervisor.create_guest(image, GuestConfig::default()).unwrap();
+    let state = |hypervisor: &Hypervisor<PageTableSv39>| hypervisor.guests[guest_id].as_ref().unwrap().state;
+    // 暂停的 guest 不会被调度
+    assert!(hypervisor.pause_guest(guest_id));
+    assert_eq!(state(hypervisor), GuestState::Paused);
+    assert!(!hypervisor.pause_guest(guest_id));
+    assert!(hypervisor.find_next_vcpu().map_or(true, |(id, _)| id != guest_id));
+    assert!(hypervisor.resume_guest(guest_id));
+    assert_eq!(state(hypervisor), GuestState::Ready);
+    assert!(!hypervisor.resume_guest(guest_id));
+    // 停止之后不能暂停或恢复
+    assert!(hypervisor.stop_guest(guest_id, GuestState::Halted));
+    assert_eq!(state(hypervisor), GuestState::Halted);
+    assert!(!hypervisor.pause_guest(guest_id));
+    assert!(!hypervisor.resume_guest(guest_id));
+    // 重置之后只有 vCPU 0 可以运行
+    assert!(hypervisor.reset_guest(guest_id));
+    assert_eq!(state(hypervisor), GuestState::Ready);
+    let guest = hypervisor.guests[guest_id].as_ref().unwrap();
+    assert_eq!(guest.vcpus[0].state, VCpuState::Ready);
+    assert!(guest.vcpus[1..].iter().all(|vcpu| vcpu.state == VCpuState::Stopped));
+    // 销毁之后槽位可以重新使用
+    assert!(hypervisor.destroy_guest(guest_id));
+    assert!(hypervisor.guests[guest_id].is_none());
+    assert!(!hypervisor.destroy_guest(guest_id));
+    assert!(!hypervisor.stop_guest(guest_id, GuestState::Crashed));
+    hdebug!("guest lifecycle test passed!");
+}
@@ -2,6 +2,8 @@
 
 use riscv::register::sstatus::{self, Sstatus, SPP};
 
+use crate::constants::csr::status::STATUS_FS;
+
 #[repr(C)]
 #[derive(Debug)]
 /// trap context structure containing sstatus, sepc and registers
@@ -18,6 +20,8 @@ pub struct TrapContext {
     pub kernel_sp: usize,
     /// Addr of trap_handler function
     pub trap_handler: usize,
+    /// hart id of the hart running this context, restored into `tp` on trap
+    pub hart_id: usize,
 }
 
 impl TrapContext {
@@ -42,10 +46,21 @@ impl TrapContext {
             kernel_satp,  // addr of page table
             kernel_sp,    // kernel stack
             trap_handler, // addr of trap_handler function
+            hart_id: 0,   // set by `__restore` before entering guest
         };
         cx.set_sp(sp); // app's user stack pointer
         cx // return initial Trap Context of app
     }
+    /// 陷入时 `sstatus.FS` 的值
+    pub fn fs(&self) -> usize {
+        self.sstatus.bits() & STATUS_FS
+    }
+    /// 设置返回 guest 时 `sstatus.FS` 的值
+    pub fn set_fs(&mut self, fs: usize) {
+        let bits = (self.sstatus.bits() & !STATUS_FS) | (fs & STATUS_FS);
+        // `Sstatus` 只包含 `sstatus` 的值，与 `trap.S` 中使用的布局相同
+        unsafe{ core::ptr::write(&mut self.sstatus as *mut Sstatus as *mut usize, bits) };
+    }
 }
 
 
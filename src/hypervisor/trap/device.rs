@@ -1,53 +1,38 @@
 use riscv::addr::BitField;
 
-use crate::constants::csr::sie::STIE_BIT;
 use crate::constants::csr::sip::STIP_BIT;
 use crate::page_table::PageTable;
 use crate::debug::PageDebug;
 use crate::guest::GuestKernel;
-use crate::sbi::set_timer;
-use crate::timer::get_default_timer;
-use crate::timer::get_time;
+use crate::timer::{get_time, set_next_trigger, tick_expired};
 
-use super::TrapContext;
-use super::decode_instruction_at_address;
-
-
-pub fn handle_qemu_virt<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, ctx: &mut TrapContext) {
-    let (len, inst) = decode_instruction_at_address(guest, ctx.sepc);
-    if let Some(inst) = inst {
-        match inst {
-            riscv_decode::Instruction::Sw(i) => {
-                let rs1 = i.rs1() as usize;
-                let rs2 = i.rs2() as usize;
-                let offset: isize = if i.imm() > 2048 { ((0b1111 << 12) | i.imm()) as i16 as isize }else{  i.imm() as isize };
-                let vaddr = (ctx.x[rs1] as isize + offset) as usize; 
-                let value = ctx.x[rs2];
-                guest.virt_device.qemu_virt_tester.mmregs[vaddr] = value as u32;
-            }
-            _ => panic!("stval: {:#x}", ctx.sepc)
-        }
+/// 时钟中断处理函数，检查当前 guest 的虚拟时钟并重新设置物理时钟，
+/// 返回 hypervisor 调度时钟是否到期
+pub fn handle_time_interrupt<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>) -> bool {
+    let now = guest.clock.now();
+    let state = guest.shadow_state_mut();
+    if state.timer_deadline() <= now {
+        // 虚拟时钟到期，`STIP` 保持挂起直到 guest 重新设置时钟，`stimecmp` 保持不变
+        state.timer_fired = true;
+        state.csrs.sip.set_bit(STIP_BIT, true);
+        state.interrupt = true;
     }
-    ctx.sepc += len;
+    // 轮询串口输入
+    guest.virt_device.uart.poll_input(get_time());
+    guest.update_external_interrupts();
+    let expired = tick_expired(get_time());
+    // 设置下次中断
+    set_next_trigger(guest.clock.to_host(guest.shadow_state().timer_deadline()));
+    expired
 }
 
-/// 时钟中断处理函数
-pub fn handle_time_interrupt<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>) {
-    let time = get_time();
-    let mut next = time + get_default_timer();
-    if guest.shadow_state.csrs.sie.get_bit(STIE_BIT) {
-        if guest.shadow_state.csrs.mtimecmp <= time {
-            // 表明此时 Guest OS 发生中断
-            guest.shadow_state.interrupt = true;
-            // 设置 sip 寄存器
-            guest.shadow_state.csrs.sip.set_bit(STIP_BIT, true);
-        }else{
-            // 未发生中断，设置下次中断
-            next = next.min(guest.shadow_state.csrs.mtimecmp)
-        }
-    }
-    // 设置下次中断
-    set_timer(next);
+/// 设置 guest 的虚拟时钟(`stime` 为 guest 时间)，并清除挂起的时钟中断
+pub fn set_virtual_timer<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, stime: usize) {
+    let state = guest.shadow_state_mut();
+    state.csrs.mtimecmp = stime;
+    state.timer_fired = false;
+    state.csrs.sip.set_bit(STIP_BIT, false);
+    set_next_trigger(guest.clock.to_host(stime));
 }
 
 #[inline(always)]
@@ -0,0 +1,56 @@
+use riscv::register::scause::Trap;
+
+use crate::debug::{PageDebug, print_guest_backtrace};
+use crate::guest::{GuestKernel, GuestState};
+use crate::page_table::PageTable;
+
+use super::TrapContext;
+use super::decode_instruction_at_address;
+
+/// Guest OS 触发的无法模拟的行为，只会导致该 guest 崩溃而不会影响 hypervisor
+#[derive(Debug)]
+pub enum GuestFault {
+    /// 访问了未知的 CSR
+    UnknownCsr(usize),
+    /// 无法识别的特权指令
+    UnrecognizedInstruction(u32),
+    /// 无法读取 guest 指令(虚拟地址没有映射)
+    InstructionFetch(usize),
+    /// 使用了不支持的指令写入页表
+    UnsupportedPageTableWrite(usize),
+    /// 非对齐的页表项写入
+    MisalignedPageTableWrite(usize),
+    /// 页表项写入的地址超出 guest 物理内存
+    InvalidPageTableAddress(usize),
+    /// guest 的影子页表区域已经用完
+    ShadowPageTableExhausted,
+    /// hypervisor 为 guest 建立的映射(跳板页、Trap Context)缺失
+    MissingHypervisorMapping(usize),
+    /// 使用了不支持的指令访问 MMIO
+    UnsupportedMmioAccess(usize),
+    /// 不支持的陷入
+    UnsupportedTrap
+}
+
+pub type GuestResult<T = ()> = Result<T, GuestFault>;
+
+/// 输出现场信息并请求将 guest 停止为崩溃状态
+pub fn crash_guest<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, ctx: &TrapContext, fault: GuestFault, cause: Trap, stval: usize) {
+    herror!("guest {} crashed: {:?}", guest.guest_id, fault);
+    herror!(
+        "scause: {:?}, stval: {:#x}, sepc: {:#x}, smode -> {}",
+        cause,
+        stval,
+        ctx.sepc,
+        guest.shadow_state().smode()
+    );
+    if let Ok((_, inst)) = decode_instruction_at_address(guest, ctx.sepc) {
+        herror!("instruction: {:?}", inst);
+    }
+    let satp = guest.shadow_state().csrs.satp;
+    if let Some(spt) = guest.current_shadow_page_table() {
+        herror!("guest backtrace:");
+        print_guest_backtrace::<P>(spt, satp, ctx);
+    }
+    guest.stop_request = Some(GuestState::Crashed);
+}
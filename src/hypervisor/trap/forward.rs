@@ -1,51 +1,147 @@
+//! 向 guest 注入陷入
+//!
+//! 按照特权级规范模拟陷入到虚拟 S 态的过程：`SPP` 保存陷入前的特权级，`SPIE` 保存 `SIE`，
+//! 并关闭 `SIE`；`stvec` 为 Vectored 模式时中断跳转到 `BASE + 4 * cause`。
+
 use riscv::addr::BitField;
 use riscv::register::{scause, stval};
 
-use crate::constants::csr::sie::SSIE_BIT;
-use crate::constants::csr::sip::{SEIP_BIT, STIP_BIT};
+use crate::constants::csr::sip::{SEIP_BIT, SSIP_BIT, STIP_BIT};
 use crate::constants::csr::status::{STATUS_SIE_BIT, STATUS_SPP_BIT};
 use crate::page_table::PageTable;
 use crate::debug::PageDebug;
-use crate::guest::GuestKernel;
+use crate::guest::{GuestKernel, ShadowState};
 use super::TrapContext;
 
-/// 检测 Guest OS 是否发生中断，若有则进行转发
+/// `scause` 的中断位
+const INTERRUPT_BIT: usize = 1 << 63;
+
+/// 按照特权级规范选择 guest 当前可以接收的中断，优先级为 SEI > SSI > STI
+pub fn pending_interrupt(state: &ShadowState) -> Option<usize> {
+    let pending = state.csrs.sie & state.csrs.sip;
+    // 处于用户态时总是可以接收 S 态中断，处于内核态时由 `SIE` 决定
+    let enabled = !state.smode() || state.csrs.sstatus.get_bit(STATUS_SIE_BIT);
+    if !enabled {
+        return None;
+    }
+    [SEIP_BIT, SSIP_BIT, STIP_BIT].into_iter().find(|&bit| pending.get_bit(bit))
+}
+
+/// 向 guest 注入陷入，`cause` 为写入 `scause` 的值，`tval` 为写入 `stval` 的值
+pub fn inject_trap(state: &mut ShadowState, ctx: &mut TrapContext, cause: usize, tval: usize) {
+    state.csrs.scause = cause;
+    state.csrs.stval = tval;
+    state.csrs.sepc = ctx.sepc;
+    state.csrs.sstatus.set_bit(STATUS_SPP_BIT, state.smode());
+    state.push_sie();
+    state.supervisor = true;
+    let base = state.csrs.stvec & !0b11;
+    ctx.sepc = if cause & INTERRUPT_BIT != 0 && state.csrs.stvec & 0b11 == 1 {
+        base + 4 * (cause & !INTERRUPT_BIT)
+    }else{
+        base
+    };
+}
+
+/// 模拟 `sret`：回到 `SPP` 中的特权级，`SIE` 恢复为 `SPIE`，`SPIE` 设置为 1，`SPP` 设置为 U
+pub fn emulate_sret(state: &mut ShadowState, ctx: &mut TrapContext) {
+    state.supervisor = state.csrs.sstatus.get_bit(STATUS_SPP_BIT);
+    state.pop_sie();
+    state.csrs.sstatus.set_bit(STATUS_SPP_BIT, false);
+    ctx.sepc = state.csrs.sepc;
+    if !state.smode() {
+        // 返回用户态之后可以接收所有使能的中断
+        state.interrupt = true;
+    }
+}
+
+/// 检测 Guest OS 是否有可以接收的中断，若有则进行转发
 pub fn maybe_forward_interrupt<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, ctx: &mut TrapContext) {
     // 没有发生中断，返回
-    if !guest.shadow_state.interrupt { return }
-    let state = &mut guest.shadow_state;
-    // 当前状态处于用户态，且开启中断并有中断正在等待
-    if (!state.smode() && state.csrs.sstatus.get_bit(STATUS_SIE_BIT)) && (state.csrs.sie & state.csrs.sip != 0) {
-        // hdebug!("forward timer interrupt: sepc -> {:#x}", ctx.sepc);
-        let cause = if state.csrs.sip.get_bit(SEIP_BIT) { 9 }
-        else if state.csrs.sip.get_bit(STIP_BIT) { 5 }
-        else if state.csrs.sip.get_bit(SSIE_BIT) { 1 }
-        else{ unreachable!() };
-
-        state.csrs.scause = (1 << 63) | cause;
-        state.csrs.stval = 0;
-        state.csrs.sepc = ctx.sepc;
-        state.push_sie();
-        // 设置 sstatus 指向 S mode
-        state.csrs.sstatus.set_bit(STATUS_SPP_BIT, true);
-        ctx.sepc = state.csrs.stvec;
-    }else{
-        state.interrupt = false;
+    if !guest.shadow_state().interrupt { return }
+    let state = guest.shadow_state_mut();
+    match pending_interrupt(state) {
+        Some(code) => inject_trap(state, ctx, INTERRUPT_BIT | code, 0),
+        None => state.interrupt = false
     }
 }
 
-/// 向 guest kernel 转发异常
+/// 向 guest kernel 转发当前的异常
 pub fn forward_exception<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, ctx: &mut TrapContext) {
-    let state = &mut guest.shadow_state;
-    state.csrs.scause = scause::read().code();
-    state.csrs.sepc = ctx.sepc;
-    state.csrs.stval = stval::read();
-    // 设置 sstatus 指向 S mode
-    state.csrs.sstatus.set_bit(STATUS_SPP_BIT, true);
-    ctx.sepc = state.csrs.stvec;
-    // 将当前中断上下文修改为中断处理地址，以便陷入内核处理
-    match guest.shadow_state.smode() {
-        true => {},
-        false => {}
-    }
-}
\ No newline at end of file
+    inject_trap(guest.shadow_state_mut(), ctx, scause::read().bits(), stval::read());
+}
+
+#[allow(unused)]
+pub fn trap_delivery_test() {
+    use crate::constants::csr::sie::{SEIE, SSIE, STIE};
+    use crate::constants::csr::sip::{SEIP, SSIP, STIP};
+    use crate::constants::csr::status::{STATUS_SIE, STATUS_SPIE, STATUS_SPP};
+    let stvec = 0x8020_0000;
+    let mut ctx = TrapContext::app_init_context(0, 0, 0, 0, 0);
+
+    // 用户态的异常：SPP = U，SPIE = SIE，SIE = 0，Vectored 模式下异常仍然跳转到 BASE
+    let mut state = ShadowState::new();
+    state.supervisor = false;
+    state.csrs.stvec = stvec | 1;
+    state.csrs.sstatus = STATUS_SIE;
+    ctx.sepc = 0x1000;
+    inject_trap(&mut state, &mut ctx, 13, 0x2000);
+    assert!(state.smode());
+    assert_eq!(state.csrs.sstatus & (STATUS_SPP | STATUS_SPIE | STATUS_SIE), STATUS_SPIE);
+    assert_eq!((state.csrs.scause, state.csrs.stval, state.csrs.sepc), (13, 0x2000, 0x1000));
+    assert_eq!(ctx.sepc, stvec);
+
+    // 内核态的异常：SPP = S，关闭中断时 SPIE = 0
+    let mut state = ShadowState::new();
+    state.csrs.stvec = stvec;
+    ctx.sepc = 0x8020_1000;
+    inject_trap(&mut state, &mut ctx, 2, 0);
+    assert_eq!(state.csrs.sstatus & (STATUS_SPP | STATUS_SPIE | STATUS_SIE), STATUS_SPP);
+    assert_eq!(state.csrs.sepc, 0x8020_1000);
+    assert_eq!(ctx.sepc, stvec);
+
+    // `sret` 回到 SPP 中的特权级，SIE = SPIE，SPIE = 1，SPP = U
+    state.csrs.sepc = 0x8020_2000;
+    state.csrs.sstatus |= STATUS_SPIE;
+    emulate_sret(&mut state, &mut ctx);
+    assert!(state.smode());
+    assert_eq!(state.csrs.sstatus & (STATUS_SPP | STATUS_SPIE | STATUS_SIE), STATUS_SPIE | STATUS_SIE);
+    assert_eq!(ctx.sepc, 0x8020_2000);
+    state.csrs.sepc = 0x3000;
+    emulate_sret(&mut state, &mut ctx);
+    assert!(!state.smode());
+    assert_eq!(ctx.sepc, 0x3000);
+
+    // 内核态只有 SIE 为 1 时才能接收中断，用户态总是可以接收中断
+    let mut state = ShadowState::new();
+    state.csrs.sie = STIE;
+    state.csrs.sip = STIP;
+    assert_eq!(pending_interrupt(&state), None);
+    state.csrs.sstatus = STATUS_SIE;
+    assert_eq!(pending_interrupt(&state), Some(STIP_BIT));
+    state.csrs.sstatus = 0;
+    state.supervisor = false;
+    assert_eq!(pending_interrupt(&state), Some(STIP_BIT));
+    // 没有使能的中断不会被接收
+    state.csrs.sie = 0;
+    assert_eq!(pending_interrupt(&state), None);
+
+    // 优先级为 SEI > SSI > STI
+    state.csrs.sie = SEIE | SSIE | STIE;
+    state.csrs.sip = SEIP | SSIP | STIP;
+    assert_eq!(pending_interrupt(&state), Some(SEIP_BIT));
+    state.csrs.sip = SSIP | STIP;
+    assert_eq!(pending_interrupt(&state), Some(SSIP_BIT));
+
+    // Vectored 模式下中断跳转到 BASE + 4 * cause
+    state.csrs.stvec = stvec | 1;
+    ctx.sepc = 0x4000;
+    inject_trap(&mut state, &mut ctx, INTERRUPT_BIT | SSIP_BIT, 0);
+    assert_eq!(state.csrs.scause, INTERRUPT_BIT | SSIP_BIT);
+    assert_eq!(ctx.sepc, stvec + 4 * SSIP_BIT);
+    // Direct 模式下中断同样跳转到 BASE
+    state.csrs.stvec = stvec;
+    inject_trap(&mut state, &mut ctx, INTERRUPT_BIT | STIP_BIT, 0);
+    assert_eq!(ctx.sepc, stvec);
+    hdebug!("trap delivery test passed!");
+}
@@ -0,0 +1,59 @@
+//! 浮点单元的延迟切换
+//!
+//! vCPU 换入时物理 `sstatus.FS` 为 Off，guest 第一次执行浮点指令时触发非法指令异常，
+//! 此时若 guest 的虚拟 `FS` 没有关闭，则标记 vCPU 使用浮点单元并重新执行该指令，
+//! 由 `trap_return` 恢复浮点寄存器；否则作为非法指令转发给 guest。
+
+use crate::constants::csr::status::{STATUS_FS, STATUS_SD, FS_OFF, FS_DIRTY};
+use crate::debug::PageDebug;
+use crate::guest::GuestKernel;
+use crate::page_table::PageTable;
+
+use super::TrapContext;
+use super::GuestResult;
+use super::forward_exception;
+use super::read_instruction_at_address;
+
+/// 是否为浮点指令(包括浮点访存指令与访问 `fflags`/`frm`/`fcsr` 的 CSR 指令)
+pub fn is_fp_instruction(inst: u32) -> bool {
+    if inst & 0b11 != 0b11 {
+        // C.FLD/C.FSD/C.FLDSP/C.FSDSP
+        let funct3 = (inst >> 13) & 0b111;
+        return matches!((inst & 0b11, funct3), (0b00, 0b001) | (0b00, 0b101) | (0b10, 0b001) | (0b10, 0b101));
+    }
+    match inst & 0x7f {
+        // LOAD-FP, STORE-FP, FMADD, FMSUB, FNMSUB, FNMADD, OP-FP
+        0b000_0111 | 0b010_0111 | 0b100_0011 | 0b100_0111 | 0b100_1011 | 0b100_1111 | 0b101_0011 => true,
+        // SYSTEM
+        0b111_0011 => {
+            let funct3 = (inst >> 12) & 0b111;
+            let csr = inst >> 20;
+            funct3 != 0 && funct3 != 0b100 && (1..=3).contains(&csr)
+        }
+        _ => false
+    }
+}
+
+/// 陷入时 guest 修改过浮点寄存器，同步到 vCPU 的浮点上下文与虚拟 `sstatus.FS`
+pub fn sync_fp_state<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, ctx: &TrapContext) {
+    if ctx.fs() == FS_DIRTY {
+        guest.vcpu_mut().fp.dirty = true;
+        guest.shadow_state_mut().csrs.sstatus |= FS_DIRTY | STATUS_SD;
+    }
+}
+
+/// 处理浮点单元关闭时执行浮点指令触发的非法指令异常，不是浮点指令时返回 `false`
+pub fn handle_fp_unavailable<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, ctx: &mut TrapContext) -> GuestResult<bool> {
+    if !is_fp_instruction(read_instruction_at_address(guest, ctx.sepc)?) {
+        return Ok(false);
+    }
+    if guest.shadow_state().csrs.sstatus & STATUS_FS == FS_OFF || guest.vcpu().fp.active {
+        // guest 关闭了浮点单元，或者浮点单元已经开启但指令本身非法
+        htracking!("forward illegal fp instruction: sepc -> {:#x}", ctx.sepc);
+        forward_exception(guest, ctx);
+    }else{
+        // 不跳过该指令，恢复浮点寄存器之后重新执行
+        guest.vcpu_mut().fp.active = true;
+    }
+    Ok(true)
+}
@@ -0,0 +1,38 @@
+//! hypocaust 半虚拟化扩展
+//!
+//! 通过固件自定义范围内的 SBI 扩展向 guest 提供 hypervisor 服务，guest 可以查询自身的编号与内存布局、
+//! 读取统计信息、主动让出处理器以及注册共享页。
+
+use crate::constants::layout::{GUEST_KERNEL_VIRT_START, KERNEL_SPACE};
+use crate::debug::PageDebug;
+use crate::guest::GuestKernel;
+use crate::guest::sbi::*;
+use crate::page_table::PageTable;
+
+/// hypocaust 扩展
+pub fn handle_hypercall<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, fid: usize, arg0: usize, arg1: usize) -> SbiRet {
+    match fid {
+        HYPOCAUST_GET_GUEST_ID => SbiRet::ok(guest.guest_id),
+        HYPOCAUST_GET_MEMORY_BASE => SbiRet::ok(GUEST_KERNEL_VIRT_START),
+        HYPOCAUST_GET_MEMORY_SIZE => SbiRet::ok(KERNEL_SPACE),
+        HYPOCAUST_GET_STAT => match guest.stats.get(arg0) {
+            Some(value) => SbiRet::ok(value),
+            None => SbiRet::error(SBI_ERR_INVALID_PARAM)
+        },
+        HYPOCAUST_YIELD => {
+            // 没有其他可运行的 vCPU 时调度器会直接返回当前 vCPU
+            guest.vcpu_mut().yielded = true;
+            SbiRet::ok(0)
+        }
+        HYPOCAUST_SET_SHARED_PAGE => {
+            // 共享页地址的高位必须为 0，`usize::MAX` 表示取消注册
+            if arg1 != 0 || !guest.register_shared_page(arg0) {
+                return SbiRet::error(SBI_ERR_INVALID_ADDRESS);
+            }
+            hdebug!("guest {} register shared page {:#x}", guest.guest_id, arg0);
+            SbiRet::ok(0)
+        }
+        HYPOCAUST_GET_STOLEN_TIME => SbiRet::ok(guest.vcpu().stolen_time),
+        _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED)
+    }
+}
@@ -1,141 +1,190 @@
 
-
-use riscv::addr::BitField;
-use riscv::register::scause;
-
 use super::TrapContext;
-use super::forward_exception;
+use super::{forward_exception, emulate_sret};
+use super::handle_sbi_call;
+use super::{GuestFault, GuestResult};
 use crate::debug::PageDebug;
-use crate::constants::csr::sip::STIP_BIT;
-use crate::constants::csr::status::STATUS_SPP_BIT;
+use crate::constants::layout::PAGE_SIZE;
 use crate::page_table::PageTable;
-use crate::sbi::{ console_putchar, set_timer, console_getchar, shutdown };
-use crate::guest::sbi::{ SBI_CONSOLE_GETCHAR, SBI_CONSOLE_PUTCHAR, SBI_SET_TIMER, SBI_SHUTDOWN };
-use crate::guest::GuestKernel;
+use crate::guest::{GuestKernel, VCpuState};
 
 
 
-/// 处理特权级指令问题
-pub fn ifault<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, ctx: &mut TrapContext) {
-    let (len, inst) = decode_instruction_at_address(guest, ctx.sepc);
+/// 处理特权级指令问题，hypervisor 不模拟的指令(`ebreak`、`mret` 等)以原来的 `scause` 转发给 guest，
+/// 只有 hypervisor 自身无法完成模拟时才返回错误
+pub fn ifault<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, ctx: &mut TrapContext) -> GuestResult {
+    let (len, inst) = match decode_instruction_at_address(guest, ctx.sepc) {
+        // 无法识别长度的指令同样是非法指令
+        Err(GuestFault::UnrecognizedInstruction(_)) => {
+            forward_exception(guest, ctx);
+            return Ok(());
+        }
+        result => result?
+    };
     if let Some(inst) = inst {
+        // guest 用户态陷入的指令除了系统调用与 CSR 访问之外都由 guest 内核处理
+        if !guest.shadow_state().smode() && !user_emulated(&inst) {
+            htracking!("forward user instruction {:?}: sepc -> {:#x}", inst, ctx.sepc);
+            forward_exception(guest, ctx);
+            return Ok(());
+        }
+        if !matches!(inst, riscv_decode::Instruction::Ecall) {
+            guest.stats.emulated_instructions += 1;
+        }
         match inst {
             riscv_decode::Instruction::Ecall => {
-                match ctx.x[17]  {
-                    SBI_SET_TIMER => {
-                        let stime = ctx.x[10];
-                        guest.shadow_state.csrs.mtimecmp = stime;
-                        set_timer(stime);
-                        guest.shadow_state.csrs.sip.set_bit(STIP_BIT, false);
-                    }
-                    SBI_CONSOLE_PUTCHAR => {
-                        let c = ctx.x[10];
-                        console_putchar(c);
-                    }
-                    SBI_CONSOLE_GETCHAR => {
-                        let c = console_getchar();
-                        ctx.x[10] = c;
-                    }
-                    SBI_SHUTDOWN => shutdown(),
-                    _ => {
-                        // hdebug!("forward exception: sepc -> {:#x}", ctx.sepc);
-                        forward_exception(guest, ctx);
-                        return;
-                    }
+                if !handle_ecall(guest, ctx)? {
+                    return Ok(());
                 }
             },
             riscv_decode::Instruction::Csrrc(i) => {
                 let mask = ctx.x[i.rs1() as usize];
-                let csr = i.csr() as usize;
-                let rd = i.rd() as usize;
-                let val = guest.get_csr(csr);
-                if mask != 0 {
-                    guest.set_csr(csr, val & !mask);
+                if !emulate_csr(guest, ctx, i.csr() as usize, i.rd() as usize, i.rs1() != 0, |val| val & !mask)? {
+                    return Ok(());
                 }
-                ctx.x[rd] = val;
             }
             riscv_decode::Instruction::Csrrs(i) => {
                 let mask = ctx.x[i.rs1() as usize];
-                let csr = i.csr() as usize;
-                let rd = i.rd() as usize;
-                let val = guest.get_csr(csr);
-                if mask != 0 {
-                    guest.set_csr(csr, val | mask);
+                if !emulate_csr(guest, ctx, i.csr() as usize, i.rd() as usize, i.rs1() != 0, |val| val | mask)? {
+                    return Ok(());
                 }
-                ctx.x[rd] = val;
             }
             // 写 CSR 指令
             riscv_decode::Instruction::Csrrw(i) => {
-                let prev = guest.get_csr(i.csr() as usize);
                 // 向 Shadow CSR 写入
                 let val = ctx.x[i.rs1() as usize];
-                guest.set_csr(i.csr() as usize, val);
-                ctx.x[i.rd() as usize] = prev;
+                if !emulate_csr(guest, ctx, i.csr() as usize, i.rd() as usize, true, |_| val)? {
+                    return Ok(());
+                }
             },
             riscv_decode::Instruction::Csrrwi(i) => {
-                let prev = guest.get_csr(i.csr() as usize);
-                guest.set_csr(i.csr() as usize, i.zimm() as usize);
-                ctx.x[i.rd() as usize] = prev;
+                let val = i.zimm() as usize;
+                if !emulate_csr(guest, ctx, i.csr() as usize, i.rd() as usize, true, |_| val)? {
+                    return Ok(());
+                }
             }
             riscv_decode::Instruction::Csrrsi(i) => {
-                let prev = guest.get_csr(i.csr() as usize);
                 let mask = i.zimm() as usize;
-                if mask != 0 {
-                    guest.set_csr(i.csr() as usize, prev | mask);
+                if !emulate_csr(guest, ctx, i.csr() as usize, i.rd() as usize, mask != 0, |val| val | mask)? {
+                    return Ok(());
                 }
-                ctx.x[i.rd() as usize] = prev;
             },
             riscv_decode::Instruction::Csrrci(i) => {
-                let prev = guest.get_csr(i.csr() as usize);
                 let mask = i.zimm() as usize;
-                if mask != 0 {
-                    guest.set_csr(i.csr() as usize, prev & !mask);
+                if !emulate_csr(guest, ctx, i.csr() as usize, i.rd() as usize, mask != 0, |val| val & !mask)? {
+                    return Ok(());
                 }
-                ctx.x[i.rd() as usize] = prev;
             }
             riscv_decode::Instruction::Sret => {
-                guest.shadow_state.pop_sie();
-                ctx.sepc = guest.get_csr(crate::constants::csr::sepc);
-                guest.shadow_state.csrs.sstatus.set_bit(STATUS_SPP_BIT, false);
-                if !guest.shadow_state.smode() {
-                    guest.shadow_state.interrupt = true;
-                }
+                emulate_sret(guest.shadow_state_mut(), ctx);
                 // hdebug!("sret: spec -> {:#x}", ctx.sepc);
-                return;
+                return Ok(());
             }
             riscv_decode::Instruction::SfenceVma(i) => {
-                if i.rs1() == 0 {
-                    // unsafe{ core::arch::asm!("sfence.vma") };
-                }else{
-                    unimplemented!()
+                // `rs1` 为 x0 时刷新所有地址，`rs2` 为 x0 时刷新所有地址空间
+                let range = match i.rs1() {
+                    0 => None,
+                    rs1 => Some((ctx.x[rs1 as usize], PAGE_SIZE))
+                };
+                let asid = match i.rs2() {
+                    0 => None,
+                    rs2 => Some(ctx.x[rs2 as usize] & 0xffff)
+                };
+                guest.sfence_vma(range, asid)?;
+                // 影子页表与 guest 页表使用相同的虚拟地址，刷新 host 中对应的地址翻译缓存
+                match range {
+                    Some((vaddr, _)) => unsafe{ core::arch::asm!("sfence.vma {}, zero", in(reg) vaddr) },
+                    None => unsafe{ core::arch::asm!("sfence.vma") }
+                }
+            }
+            riscv_decode::Instruction::Wfi => {
+                // 不考虑 `sstatus.SIE`，只要有使能的中断等待处理就继续运行，
+                // 否则让出处理器直到虚拟时钟到期或者收到中断
+                let now = guest.clock.now();
+                if !guest.vcpu().interrupt_pending(now) {
+                    guest.vcpu_mut().state = VCpuState::Idle;
                 }
             }
-            riscv_decode::Instruction::Wfi => {}
             _ => {
-                let paddr = guest.translate_guest_vaddr(ctx.sepc).unwrap();
-                let inst = unsafe{ core::ptr::read(paddr as *const u32) };
-                panic!("Unrecognized instruction, sepc: {:#x}, scause: {:?}, inst: {:#x}", ctx.sepc, scause::read().cause(), inst)
+                htracking!("forward unemulated instruction {:?}: sepc -> {:#x}", inst, ctx.sepc);
+                forward_exception(guest, ctx);
+                return Ok(());
             }
         }
     }else{ 
-        forward_exception(guest, ctx) 
+        forward_exception(guest, ctx);
+        return Ok(());
     }
     ctx.sepc += len;
+    Ok(())
 }
 
-/// decode instruction from Guest OS address
-pub fn decode_instruction_at_address<P: PageTable + PageDebug>(guest: &GuestKernel<P>, addr: usize) -> (usize, Option<riscv_decode::Instruction>) {
-    let paddr = guest.translate_guest_vaddr(addr).unwrap();
-    let i1 = unsafe{ core::ptr::read(paddr as *const u16) };
-    let len = riscv_decode::instruction_length(i1);
-    let inst = match len {
-        2 => i1 as u32,
-        4 => unsafe{ core::ptr::read(paddr as *const u32) },
-        _ => unreachable!()
+/// guest 用户态执行时需要 hypervisor 处理的指令：系统调用与 CSR 访问
+fn user_emulated(inst: &riscv_decode::Instruction) -> bool {
+    use riscv_decode::Instruction::*;
+    matches!(inst, Ecall | Csrrw(_) | Csrrs(_) | Csrrc(_) | Csrrwi(_) | Csrrsi(_) | Csrrci(_))
+}
+
+/// 模拟 CSR 读改写指令，`write` 为 `false` 时只读取 CSR；
+/// guest 无权访问或者访问了未知的 CSR 时向 guest 转发非法指令异常并返回 `false`
+fn emulate_csr<P: PageTable + PageDebug>(
+    guest: &mut GuestKernel<P>,
+    ctx: &mut TrapContext,
+    csr: usize,
+    rd: usize,
+    write: bool,
+    update: impl FnOnce(usize) -> usize
+) -> GuestResult<bool> {
+    let prev = match guest.get_csr(csr) {
+        Ok(prev) if guest.csr_accessible(csr, write) => prev,
+        Ok(_) | Err(GuestFault::UnknownCsr(_)) => {
+            htracking!("forward illegal csr access {:#x}: sepc -> {:#x}", csr, ctx.sepc);
+            forward_exception(guest, ctx);
+            return Ok(false);
+        }
+        Err(fault) => return Err(fault)
     };
-    (len, riscv_decode::decode(inst).ok())
+    if write {
+        guest.set_csr(csr, update(prev))?;
+    }
+    if rd != 0 {
+        ctx.x[rd] = prev;
+    }
+    Ok(true)
 }
 
+/// 处理 guest 的 `ecall`，返回是否作为 SBI 调用处理(需要跳过 `ecall` 指令)
+/// 
+/// guest 用户态的 `ecall` 是用户程序的系统调用，总是以 `scause = 8` 转发给 guest kernel，
+/// 只有 guest 内核态的 `ecall` 才是 SBI 调用
+pub fn handle_ecall<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, ctx: &mut TrapContext) -> GuestResult<bool> {
+    if !guest.shadow_state().smode() {
+        // hdebug!("forward exception: sepc -> {:#x}", ctx.sepc);
+        forward_exception(guest, ctx);
+        return Ok(false);
+    }
+    handle_sbi_call(guest, ctx)?;
+    guest.stats.sbi_calls += 1;
+    Ok(true)
+}
+
+/// read raw instruction from Guest OS address
+pub fn read_instruction_at_address<P: PageTable + PageDebug>(guest: &GuestKernel<P>, addr: usize) -> GuestResult<u32> {
+    let paddr = guest.translate_guest_vaddr(addr).ok_or(GuestFault::InstructionFetch(addr))?;
+    let i1 = unsafe{ core::ptr::read(paddr as *const u16) };
+    match riscv_decode::instruction_length(i1) {
+        2 => Ok(i1 as u32),
+        4 => Ok(unsafe{ core::ptr::read(paddr as *const u32) }),
+        _ => Err(GuestFault::UnrecognizedInstruction(i1 as u32))
+    }
+}
+
+/// decode instruction from Guest OS address
+pub fn decode_instruction_at_address<P: PageTable + PageDebug>(guest: &GuestKernel<P>, addr: usize) -> GuestResult<(usize, Option<riscv_decode::Instruction>)> {
+    let inst = read_instruction_at_address(guest, addr)?;
+    let len = riscv_decode::instruction_length(inst as u16);
+    Ok((len, riscv_decode::decode(inst).ok()))
+}
 
 
 
@@ -145,3 +194,78 @@ pub fn decode_instruction_at_address<P: PageTable + PageDebug>(guest: &GuestKern
 
 
 
+
+
+#[allow(unused)]
+pub fn ecall_test() {
+    use crate::guest::ShadowState;
+    use crate::guest::sbi::*;
+    use crate::constants::layout::GUEST_KERNEL_VIRT_END;
+    use crate::hypervisor::HYPOCAUST;
+    let mut inner = HYPOCAUST.lock();
+    let guest = inner.as_mut().unwrap().guests[0].as_mut().unwrap();
+    guest.active_vcpu = 0;
+    // 保存 guest 的状态，测试结束后恢复
+    let shadow_state = core::mem::replace(guest.shadow_state_mut(), ShadowState::new());
+    let stats = guest.stats;
+    let mut ctx = TrapContext::app_init_context(0, 0, 0, 0, 0);
+    let stvec = 0x8020_0000;
+
+    // guest 用户态的系统调用 1 不能被当作 SBI_CONSOLE_PUTCHAR
+    guest.shadow_state_mut().csrs.stvec = stvec;
+    guest.shadow_state_mut().supervisor = false;
+    ctx.sepc = 0x1000;
+    ctx.x[17] = SBI_CONSOLE_PUTCHAR;
+    ctx.x[10] = b'A' as usize;
+    // 模拟从 guest 用户态陷入
+    unsafe{ core::arch::asm!("csrw scause, {}", in(reg) 8) };
+    assert!(!handle_ecall(guest, &mut ctx).unwrap());
+    assert_eq!(guest.shadow_state().csrs.scause, 8);
+    assert_eq!(guest.shadow_state().csrs.sepc, 0x1000);
+    assert!(guest.shadow_state().smode());
+    assert_eq!(ctx.sepc, stvec);
+    assert_eq!(ctx.x[10], b'A' as usize);
+    assert!(guest.virt_device.console.line_buffer.is_empty());
+
+    // guest 内核态的 ecall 作为 SBI 调用处理
+    ctx.sepc = 0x8020_1000;
+    ctx.x[17] = EID_BASE;
+    ctx.x[16] = BASE_GET_SPEC_VERSION;
+    assert!(handle_ecall(guest, &mut ctx).unwrap());
+    assert_eq!(ctx.x[10], SBI_SUCCESS as usize);
+    assert_eq!(ctx.x[11], SBI_SPEC_VERSION);
+    assert_eq!(ctx.sepc, 0x8020_1000);
+
+    // guest 内核态未实现的扩展返回 SBI_ERR_NOT_SUPPORTED，而不是转发给 guest 自身
+    ctx.x[17] = 0x0B00_0000;
+    assert!(handle_ecall(guest, &mut ctx).unwrap());
+    assert_eq!(ctx.x[10], SBI_ERR_NOT_SUPPORTED as usize);
+    assert_eq!(ctx.sepc, 0x8020_1000);
+
+    // hypervisor 不模拟的 `ebreak` 以断点异常转发给 guest 内核，而不是使 guest 崩溃
+    let inst_va = GUEST_KERNEL_VIRT_END - PAGE_SIZE;
+    let inst_pa = guest.translate_guest_vaddr(inst_va).unwrap();
+    let saved = unsafe{ core::ptr::read(inst_pa as *const u32) };
+    unsafe{ core::ptr::write(inst_pa as *mut u32, 0x0010_0073) };
+    ctx.sepc = inst_va;
+    unsafe{ core::arch::asm!("csrw scause, {}", in(reg) 3) };
+    ifault(guest, &mut ctx).unwrap();
+    assert_eq!(guest.shadow_state().csrs.scause, 3);
+    assert_eq!(guest.shadow_state().csrs.sepc, inst_va);
+    assert_eq!(ctx.sepc, stvec);
+    // guest 用户态的特权指令以非法指令异常转发给 guest 内核
+    unsafe{ core::ptr::write(inst_pa as *mut u32, 0x3020_0073) };
+    guest.shadow_state_mut().supervisor = false;
+    ctx.sepc = inst_va;
+    unsafe{ core::arch::asm!("csrw scause, {}", in(reg) 2) };
+    ifault(guest, &mut ctx).unwrap();
+    assert!(guest.shadow_state().smode());
+    assert_eq!(guest.shadow_state().csrs.scause, 2);
+    assert_eq!(guest.shadow_state().csrs.sepc, inst_va);
+    assert_eq!(ctx.sepc, stvec);
+    unsafe{ core::ptr::write(inst_pa as *mut u32, saved) };
+
+    *guest.shadow_state_mut() = shadow_state;
+    guest.stats = stats;
+    hdebug!("ecall test passed!");
+}
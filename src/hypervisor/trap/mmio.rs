@@ -0,0 +1,173 @@
+//! MMIO 访存指令模拟
+//!
+//! 解码所有 RV64 load/store 指令(包括压缩指令)，计算有效地址并翻译为 guest 物理地址，
+//! 根据 guest 物理地址分发给对应的模拟设备，读取的结果按照符号扩展或零扩展写回寄存器。
+
+use crate::debug::PageDebug;
+use crate::guest::GuestKernel;
+use crate::page_table::PageTable;
+
+use super::TrapContext;
+use super::GuestResult;
+use super::read_instruction_at_address;
+
+/// 访存类型
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryAccess {
+    /// 读取 `width` 字节写入 `rd`，`signed` 为 `true` 时进行符号扩展
+    Load { rd: usize, width: usize, signed: bool },
+    /// 将 `rs2` 的低 `width` 字节写入内存
+    Store { rs2: usize, width: usize }
+}
+
+/// 解码后的访存指令
+#[derive(Clone, Copy, Debug)]
+pub struct DecodedAccess {
+    pub access: MemoryAccess,
+    /// 基址寄存器
+    pub rs1: usize,
+    /// 符号扩展之后的偏移量
+    pub offset: isize,
+    /// 指令长度
+    pub len: usize
+}
+
+impl DecodedAccess {
+    /// 访存的有效地址(GVA)
+    pub fn vaddr(&self, ctx: &TrapContext) -> usize {
+        ctx.x[self.rs1].wrapping_add(self.offset as usize)
+    }
+}
+
+/// 解码 load/store 指令，不是整数访存指令时返回 `None`
+pub fn decode_memory_access(inst: u32) -> Option<DecodedAccess> {
+    if inst & 0b11 == 0b11 {
+        decode_standard(inst)
+    }else{
+        decode_compressed(inst as u16)
+    }
+}
+
+fn decode_standard(inst: u32) -> Option<DecodedAccess> {
+    let funct3 = (inst >> 12) & 0b111;
+    let rd = ((inst >> 7) & 0x1f) as usize;
+    let rs1 = ((inst >> 15) & 0x1f) as usize;
+    let rs2 = ((inst >> 20) & 0x1f) as usize;
+    let (access, offset) = match inst & 0x7f {
+        // LOAD
+        0b000_0011 => {
+            let (width, signed) = match funct3 {
+                0b000 => (1, true),
+                0b001 => (2, true),
+                0b010 => (4, true),
+                0b011 => (8, true),
+                0b100 => (1, false),
+                0b101 => (2, false),
+                0b110 => (4, false),
+                _ => return None
+            };
+            let offset = (inst as i32 >> 20) as isize;
+            (MemoryAccess::Load { rd, width, signed }, offset)
+        }
+        // STORE
+        0b010_0011 => {
+            let width = match funct3 {
+                0b000 => 1,
+                0b001 => 2,
+                0b010 => 4,
+                0b011 => 8,
+                _ => return None
+            };
+            let offset = (((inst as i32 >> 25) << 5) | ((inst >> 7) & 0x1f) as i32) as isize;
+            (MemoryAccess::Store { rs2, width }, offset)
+        }
+        _ => return None
+    };
+    Some(DecodedAccess { access, rs1, offset, len: 4 })
+}
+
+fn decode_compressed(inst: u16) -> Option<DecodedAccess> {
+    let inst = inst as usize;
+    let funct3 = inst >> 13;
+    // 压缩指令中 3 位的寄存器编号表示 x8 ~ x15
+    let rd_prime = 8 + ((inst >> 2) & 0b111);
+    let rs1_prime = 8 + ((inst >> 7) & 0b111);
+    // C.LW/C.SW 与 C.LD/C.SD 的偏移量
+    let word_offset = ((inst >> 10) & 0b111) << 3 | ((inst >> 6) & 1) << 2 | ((inst >> 5) & 1) << 6;
+    let double_offset = ((inst >> 10) & 0b111) << 3 | ((inst >> 5) & 0b11) << 6;
+    let (access, rs1, offset) = match (inst & 0b11, funct3) {
+        // C.LW
+        (0b00, 0b010) => (MemoryAccess::Load { rd: rd_prime, width: 4, signed: true }, rs1_prime, word_offset),
+        // C.LD
+        (0b00, 0b011) => (MemoryAccess::Load { rd: rd_prime, width: 8, signed: true }, rs1_prime, double_offset),
+        // C.SW
+        (0b00, 0b110) => (MemoryAccess::Store { rs2: rd_prime, width: 4 }, rs1_prime, word_offset),
+        // C.SD
+        (0b00, 0b111) => (MemoryAccess::Store { rs2: rd_prime, width: 8 }, rs1_prime, double_offset),
+        // C.LWSP
+        (0b10, 0b010) => {
+            let offset = ((inst >> 12) & 1) << 5 | ((inst >> 4) & 0b111) << 2 | ((inst >> 2) & 0b11) << 6;
+            (MemoryAccess::Load { rd: (inst >> 7) & 0x1f, width: 4, signed: true }, 2, offset)
+        }
+        // C.LDSP
+        (0b10, 0b011) => {
+            let offset = ((inst >> 12) & 1) << 5 | ((inst >> 5) & 0b11) << 3 | ((inst >> 2) & 0b111) << 6;
+            (MemoryAccess::Load { rd: (inst >> 7) & 0x1f, width: 8, signed: true }, 2, offset)
+        }
+        // C.SWSP
+        (0b10, 0b110) => {
+            let offset = ((inst >> 9) & 0b1111) << 2 | ((inst >> 7) & 0b11) << 6;
+            (MemoryAccess::Store { rs2: (inst >> 2) & 0x1f, width: 4 }, 2, offset)
+        }
+        // C.SDSP
+        (0b10, 0b111) => {
+            let offset = ((inst >> 10) & 0b111) << 3 | ((inst >> 7) & 0b111) << 6;
+            (MemoryAccess::Store { rs2: (inst >> 2) & 0x1f, width: 8 }, 2, offset)
+        }
+        _ => return None
+    };
+    Some(DecodedAccess { access, rs1, offset: offset as isize, len: 2 })
+}
+
+/// 将读取的 `width` 字节按照符号扩展或零扩展为 64 位
+fn extend(value: usize, width: usize, signed: bool) -> usize {
+    let shift = 64 - width * 8;
+    if signed {
+        (((value << shift) as isize) >> shift) as usize
+    }else{
+        (value << shift) >> shift
+    }
+}
+
+/// 模拟 guest 对 MMIO 设备的访问，访问的地址不属于任何模拟设备时返回 `false`
+pub fn handle_mmio<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, ctx: &mut TrapContext) -> GuestResult<bool> {
+    let inst = read_instruction_at_address(guest, ctx.sepc)?;
+    let decoded = match decode_memory_access(inst) {
+        Some(decoded) => decoded,
+        None => return Ok(false)
+    };
+    let paddr = match guest.guest_vaddr_to_paddr(decoded.vaddr(ctx)) {
+        Some(paddr) => paddr,
+        None => return Ok(false)
+    };
+    let device = match guest.virt_device.mmio_device(paddr) {
+        Some(device) => device,
+        None => return Ok(false)
+    };
+    let offset = paddr - device.base();
+    match decoded.access {
+        MemoryAccess::Load { rd, width, signed } => {
+            let value = extend(device.read(offset, width), width, signed);
+            if rd != 0 {
+                ctx.x[rd] = value;
+            }
+        }
+        MemoryAccess::Store { rs2, width } => {
+            device.write(offset, width, extend(ctx.x[rs2], width, false));
+        }
+    }
+    // 访问设备寄存器可能改变设备的中断状态(例如 PLIC 的 claim/complete)
+    guest.update_external_interrupts();
+    ctx.sepc += decoded.len;
+    Ok(true)
+}
@@ -16,22 +16,35 @@ mod inst_fault;
 mod page_fault;
 mod device;
 mod forward;
+mod fault;
+mod sbi;
+mod hypercall;
+mod mmio;
+mod fpu;
 
-use crate::constants::layout::{TRAMPOLINE, TRAP_CONTEXT};
+use crate::constants::layout::{TRAMPOLINE, trap_context_position};
 use crate::debug::print_hypervisor_backtrace;
-use crate::hypervisor::HYPOCAUST;
+use crate::hypervisor::{HYPOCAUST, schedule};
+use crate::guest::{AccessType, GuestState};
 
 use core::arch::{asm, global_asm};
 use riscv::register::{
     mtvec::TrapMode,
     scause::{self, Exception, Interrupt, Trap},
-    sie, stval, stvec, sepc, sscratch
+    sie, sip, stval, stvec, sepc, sscratch
 };
 pub use context::TrapContext;
-use self::inst_fault::{ifault, decode_instruction_at_address};
+use self::inst_fault::{ifault, decode_instruction_at_address, read_instruction_at_address};
+pub use self::inst_fault::ecall_test;
 use self::page_fault::handle_page_fault;
-use self::device::{ handle_qemu_virt, handle_time_interrupt };
-use self::forward::{forward_exception, maybe_forward_interrupt};
+pub use self::page_fault::mmio_permission_test;
+use self::device::handle_time_interrupt;
+pub use self::device::set_virtual_timer;
+use self::forward::{forward_exception, maybe_forward_interrupt, emulate_sret};
+pub use self::forward::trap_delivery_test;
+use self::sbi::handle_sbi_call;
+use self::fpu::{sync_fp_state, handle_fp_unavailable};
+pub use self::fault::{GuestFault, GuestResult, crash_guest};
 
 
 global_asm!(include_str!("trap.S"));
@@ -41,6 +54,8 @@ global_asm!(include_str!("trap.S"));
 /// initialize CSR `stvec` as the entry of `__alltraps`
 pub fn init() {
     set_kernel_trap_entry();
+    // guest 读取计数器时陷入 hypervisor，从而读取虚拟时钟并检查 guest 的 `scounteren`
+    unsafe{ asm!("csrw scounteren, zero") };
 }
 
 fn set_kernel_trap_entry() {
@@ -70,51 +85,106 @@ pub fn disable_timer_interrupt() {
     unsafe{ sie::clear_stimer(); }
 }
 
+/// enable software interrupt in sie CSR, used by other harts to kick running vCPUs
+pub fn enable_software_interrupt() {
+    unsafe { sie::set_ssoft(); }
+}
+
+/// enable external interrupt in sie CSR
+pub fn enable_external_interrupt() {
+    unsafe { sie::set_sext(); }
+}
+
 
 #[no_mangle]
 /// handle an interrupt, exception, or system call from user space
 pub fn trap_handler() -> ! {
     set_kernel_trap_entry();
-    unsafe{ HYPOCAUST.force_unlock(); }
-    let mut hypervisor = HYPOCAUST.lock();
-    let hypervisor = {&mut *hypervisor}.as_mut().unwrap();
+    let mut inner = HYPOCAUST.lock();
+    let hypervisor = inner.as_mut().unwrap();
     let ctx = hypervisor.current_trap_cx();
     let scause = scause::read();
     let stval = stval::read();
+    // 外部中断需要访问所有 guest，在获取当前 guest 之前处理
+    if matches!(scause.cause(), Trap::Interrupt(Interrupt::SupervisorExternal)) {
+        hypervisor.handle_external_interrupt();
+    }
     // get guest kernel
     let guest = hypervisor.current_guest();
-    match scause.cause() {
+    guest.stats.traps += 1;
+    sync_fp_state(guest, ctx);
+    // 时间片用完或 guest 不再可以运行时需要切换 guest
+    let mut need_schedule = false;
+    let result = match scause.cause() {
         Trap::Exception(Exception::UserEnvCall) => {
-            ifault(guest, ctx);
+            ifault(guest, ctx)
         },
         Trap::Exception(Exception::Breakpoint) => { 
-            ifault(guest, ctx);
+            ifault(guest, ctx)
         }
-        Trap::Exception(Exception::StorePageFault) => {
-            if !handle_page_fault(guest, ctx) {
-                htracking!("forward page exception sepc -> {:#x}", ctx.sepc);
-                forward_exception(guest, ctx);
-            }
+        Trap::Exception(exception @ (Exception::LoadPageFault | Exception::StorePageFault | Exception::InstructionPageFault)) => {
+            let access = match exception {
+                Exception::LoadPageFault => AccessType::Load,
+                Exception::StorePageFault => AccessType::Store,
+                _ => AccessType::Fetch
+            };
+            handle_page_fault(guest, ctx, access).map(|handled| {
+                if handled {
+                    guest.stats.page_faults += 1;
+                }else{
+                    // guest 页表中真实存在的页错误，`scause` 与 `stval` 原样转发
+                    htracking!("forward page exception sepc -> {:#x}", ctx.sepc);
+                    forward_exception(guest, ctx);
+                }
+            })
         }
         Trap::Exception(Exception::IllegalInstruction) => {
-            ifault(guest, ctx);
+            // 浮点单元关闭时执行的浮点指令
+            handle_fp_unavailable(guest, ctx).and_then(|handled| {
+                if handled { Ok(()) } else { ifault(guest, ctx) }
+            })
         }
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
-            handle_time_interrupt(guest);
-            // 可能转发中断
-            maybe_forward_interrupt(guest, ctx);
+            guest.stats.timer_interrupts += 1;
+            need_schedule = handle_time_interrupt(guest);
+            Ok(())
         },
-        _ => {  
-            panic!(
-                "Unsupported trap {:?}, stval = {:#x} spec: {:#x} smode -> {}!",
-                scause.cause(),
-                stval,
-                ctx.sepc,
-                guest.shadow_state.smode()
-            );
+        Trap::Interrupt(Interrupt::SupervisorExternal) => Ok(()),
+        // 其他核发送的 IPI，guest 等待处理的中断在返回之前注入
+        Trap::Interrupt(Interrupt::SupervisorSoft) => {
+            unsafe{ sip::clear_ssoft() };
+            Ok(())
         }
+        _ => Err(GuestFault::UnsupportedTrap)
+    };
+    // 无法模拟的行为只会使当前 guest 崩溃
+    if let Err(fault) = result {
+        crash_guest(guest, ctx, fault, scause.cause(), stval);
+    }
+    // guest 关机或崩溃时通过生命周期接口停止 guest，释放其拥有的物理中断源
+    if let Some(state) = guest.stop_request.take() {
+        let guest_id = guest.guest_id;
+        hypervisor.stop_guest(guest_id, state);
+    }
+    let guest = hypervisor.current_guest();
+    // vCPU 被重置时从新的入口地址开始运行，必须在模拟完指令之后设置
+    if guest.vcpu().runnable() {
+        let guest_id = guest.guest_id;
+        guest.vcpu_mut().prepare_run(guest_id);
+    }
+    // 当前 guest 被暂停、停止、当前 vCPU 不再运行或者主动让出时需要让出处理器
+    let yielded = core::mem::take(&mut guest.vcpu_mut().yielded);
+    if !guest.runnable() || !guest.vcpu().runnable() || yielded {
+        need_schedule = true;
+    }
+    // guest complete 虚拟中断源之后在 host 上 complete 物理中断源
+    hypervisor.complete_external_interrupts();
+    // 向其他核上有新的中断等待处理的 vCPU 发送 IPI
+    hypervisor.kick_vcpus();
+    drop(inner);
+    if need_schedule {
+        schedule();
     }
-    drop(hypervisor);
     trap_return();
 }
 
@@ -124,11 +194,29 @@ pub fn trap_handler() -> ! {
 /// finally, jump to new addr of __restore asm function
 pub fn trap_return() -> ! {
     set_user_trap_entry();
-    let trap_cx_ptr = TRAP_CONTEXT;
-    unsafe{ HYPOCAUST.force_unlock(); }
-    let hypervisor = HYPOCAUST.lock();
-    let hypervisor = {&*hypervisor}.as_ref().unwrap();
-    let user_satp = hypervisor.current_user_token();
+    // 重新加载当前 vCPU 的 Trap Context 与影子页表，vCPU 可能在 `schedule` 中被切换
+    let (trap_cx_ptr, user_satp) = loop {
+        let mut inner = HYPOCAUST.lock();
+        let hypervisor = inner.as_mut().unwrap();
+        hypervisor.finish_switch();
+        let guest = hypervisor.current_guest();
+        // 返回 guest 之前注入可以接收的中断，陷入会改变 guest 的特权级
+        let ctx = guest.vcpu().trap_cx();
+        maybe_forward_interrupt(guest, ctx);
+        // 根据当前 vCPU 的视图切换影子根页表，无法建立影子页表时 guest 崩溃并切换到其他 vCPU
+        if let Err(fault) = guest.update_shadow_view() {
+            herror!("guest {} failed to build shadow page table: {:?}", guest.guest_id, fault);
+            let guest_id = guest.guest_id;
+            hypervisor.stop_guest(guest_id, GuestState::Crashed);
+            drop(inner);
+            schedule();
+            continue;
+        }
+        // 影子页表区域被回收时其他核上的 vCPU 需要陷入并切换根页表
+        hypervisor.kick_vcpus();
+        hypervisor.prepare_fp();
+        break (trap_context_position(hypervisor.current_vcpu_id()), hypervisor.current_user_token());
+    };
     extern "C" {
         fn __alltraps();
         fn __restore();
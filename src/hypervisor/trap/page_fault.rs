@@ -1,69 +1,132 @@
 use riscv::register::stval;
 
-use crate::page_table::{PageTable,  PageTableEntry};
-use crate::debug::{PageDebug, print_guest_backtrace};
-use crate::guest::{GuestKernel, gpa2hpa, PageTableRoot};
-use super::{ decode_instruction_at_address, handle_qemu_virt}; 
+use crate::page_table::{PageTable, PageTableEntry, VirtPageNum};
+use crate::debug::PageDebug;
+use crate::guest::{GuestKernel, gpa2hpa, is_guest_memory, PageTableRoot, AccessType};
+use super::read_instruction_at_address;
+use super::mmio::{handle_mmio, decode_memory_access, MemoryAccess, DecodedAccess};
 
 use super::TrapContext;
+use super::{GuestFault, GuestResult};
 
-pub fn handle_page_fault<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, ctx: &mut TrapContext) -> bool {
-    let shadow = guest.shadow();
-    if shadow == PageTableRoot::GPA {
-        hdebug!("Page fault without paging enabled?");
-        return false;
+/// 处理 guest 的页错误，返回 `false` 时说明 guest 页表本身不允许此次访问，需要转发给 guest
+pub fn handle_page_fault<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, ctx: &mut TrapContext, access: AccessType) -> GuestResult<bool> {
+    let guest_va = stval::read();
+    // guest 页表不允许的访问(包括 guest 用户态访问内核映射的设备)由 guest 内核处理
+    if !guest.guest_access_permitted(guest_va, access) {
+        return Ok(false);
+    }
+    // 处理 `MMIO`，没有开启分页时同样可能访问设备，取指错误时无法读取指令
+    if access != AccessType::Fetch && handle_mmio(guest, ctx)? {
+        return Ok(true);
     }
-    if shadow == PageTableRoot::UVA {
-        // 用户态触发异常，进行转发
-        hwarning!("Page fault from U mode?");
-        return false;
+    // guest 内核写入被设置为只读的页表页
+    if access == AccessType::Store && guest.shadow() == PageTableRoot::GVA && handle_page_table_write(guest, ctx, guest_va)? {
+        return Ok(true);
     }
+    // guest 页表允许此次访问，影子页表项已经过期
+    if guest.fix_shadow_page_table(guest_va)? {
+        htracking!("fix stale shadow page table entry: {:#x}", guest_va);
+        return Ok(true);
+    }
+    hwarning!("Page fault permitted by guest page table: {:#x}, sepc -> {:#x}", guest_va, ctx.sepc);
+    Ok(false)
+}
 
-    let guest_va = stval::read();
-    if guest_va % core::mem::size_of::<PageTableEntry>() != 0 {
-        hwarning!("guest va: {:#x}, sepc: {:#x}", guest_va, ctx.sepc);
-        print_guest_backtrace::<P>(&guest.shadow_state.shadow_page_tables.guest_page_table().unwrap(), guest.shadow_state.csrs.satp, ctx)
+/// 模拟 guest 内核对页表页的写入，并同步到影子页表
+fn handle_page_table_write<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, ctx: &mut TrapContext, guest_va: usize) -> GuestResult<bool> {
+    // 页表页在影子页表中被设置为只读
+    match guest.translate_guest_vpte(VirtPageNum::from(guest_va >> 12)) {
+        Some(spte) if spte.is_valid() && !spte.writable() => {},
+        _ => return Ok(false)
     }
-    assert_eq!(guest_va % core::mem::size_of::<PageTableEntry>(), 0);
     let sepc = ctx.sepc;
-    let (len, inst) = decode_instruction_at_address(guest, sepc);
-    // 处理 `MMIO`
-    if guest.virt_device.qemu_virt_tester.in_region(guest_va){
-        handle_qemu_virt(guest, ctx);
-        ctx.sepc += len;
-        return true;
+    let inst = read_instruction_at_address(guest, sepc)?;
+    if guest_va % core::mem::size_of::<PageTableEntry>() != 0 {
+        return Err(GuestFault::MisalignedPageTableWrite(guest_va));
     }
 
-    let mut pte = 0;
-    if let Some(_translation) = guest.translate_guest_vaddr(guest_va) {
-        // 获得翻译后的物理地址
-        if let Some(inst) = inst {
-            match inst {
-                riscv_decode::Instruction::Sd(i) => {
-                    let rs1 = i.rs1() as usize;
-                    let rs2 = i.rs2() as usize;
-                    let offset: isize = if i.imm() > 2048 { ((0b1111 << 12) | i.imm()) as i16 as isize }else{  i.imm() as isize };
-                    let vaddr = (ctx.x[rs1] as isize + offset) as usize; 
-                    assert_eq!(vaddr, guest_va);
-                    pte = ctx.x[rs2];
-                },
-                riscv_decode::Instruction::Sb(_) | riscv_decode::Instruction::Sw(_) => {
-                    panic!("Unsporrted instruction sepc -> {:#x}, stval: {:#x}", ctx.sepc, stval::read());
-                }
-                _ => { return false }
+    // 获得翻译后的物理地址
+    let (pte, len) = match decode_memory_access(inst) {
+        Some(decoded @ DecodedAccess{ access: MemoryAccess::Store { rs2, width: 8 }, .. }) => {
+            if decoded.vaddr(ctx) != guest_va {
+                return Err(GuestFault::UnsupportedPageTableWrite(guest_va));
             }
+            (ctx.x[rs2], decoded.len)
         }
-        let pte = PageTableEntry{ bits: pte };       
-        let guest_pte_addr = gpa2hpa(guest_va, guest.guest_id);
-        if guest_pte_addr >=  0x4000000000 {
-            print_guest_backtrace(guest.shadow_state.shadow_page_tables.guest_page_table().unwrap(), guest.shadow_state.csrs.satp, ctx);
-            panic!("guest va -> {:#x}, guest_pte_addr: {:#x}, sepc: {:#x}, translation: {:#x}", guest_va, guest_pte_addr, ctx.sepc, _translation);
-        }
-        unsafe{ core::ptr::write(guest_pte_addr as *mut usize, pte.bits)}
-
-        guest.synchronize_page_table(guest_va, pte);
-        ctx.sepc += len;
-        return true;
+        _ => return Err(GuestFault::UnsupportedPageTableWrite(guest_va))
+    };
+    let pte = PageTableEntry{ bits: pte };
+    // guest 内核恒等映射页表页，页表页必须位于 guest 内存中
+    if !is_guest_memory(guest_va) {
+        return Err(GuestFault::InvalidPageTableAddress(guest_va));
     }
-    false
-}
\ No newline at end of file
+    let guest_pte_addr = gpa2hpa(guest_va, guest.guest_id);
+    unsafe{ core::ptr::write(guest_pte_addr as *mut usize, pte.bits)}
+
+    guest.synchronize_page_table(guest_va, pte)?;
+    ctx.sepc += len;
+    Ok(true)
+}
+
+/// guest 页表只允许内核态访问的设备，guest 用户态访问时转发页错误，内核态访问时模拟
+#[allow(unused)]
+pub fn mmio_permission_test() {
+    use alloc::vec::Vec;
+    use crate::constants::layout::{GUEST_KERNEL_VIRT_END, PAGE_SIZE};
+    use crate::device_emu::UART_BASE;
+    use crate::guest::ShadowState;
+    use crate::hypervisor::HYPOCAUST;
+    use crate::page_table::{PhysPageNum, PTEFlags};
+    let mut inner = HYPOCAUST.lock();
+    let guest = inner.as_mut().unwrap().guests[0].as_mut().unwrap();
+    guest.active_vcpu = 0;
+    let host_page = |gpa: usize| PhysPageNum::from(gpa2hpa(gpa, 0) >> 12);
+    // guest 内存最后四页分别作为三级页表以及代码页，测试结束后恢复原来的内容
+    let (root, l1, l0, code) = (
+        GUEST_KERNEL_VIRT_END - 4 * PAGE_SIZE,
+        GUEST_KERNEL_VIRT_END - 3 * PAGE_SIZE,
+        GUEST_KERNEL_VIRT_END - 2 * PAGE_SIZE,
+        GUEST_KERNEL_VIRT_END - PAGE_SIZE
+    );
+    let saved: Vec<Vec<u8>> = [root, l1, l0, code].iter().map(|&gpa| host_page(gpa).get_bytes_array().to_vec()).collect();
+    let shadow_state = core::mem::replace(guest.shadow_state_mut(), ShadowState::new());
+    let stats = guest.stats;
+    let scratch = guest.virt_device.uart.scratch;
+
+    // 串口只映射给 guest 内核
+    [root, l1, l0].iter().for_each(|&gpa| host_page(gpa).get_bytes_array().fill(0));
+    let idxs = VirtPageNum::from(UART_BASE >> 12).indexes();
+    host_page(root).get_pte_array()[idxs[0]] = PageTableEntry::new(PhysPageNum::from(l1 >> 12), PTEFlags::V);
+    host_page(l1).get_pte_array()[idxs[1]] = PageTableEntry::new(PhysPageNum::from(l0 >> 12), PTEFlags::V);
+    host_page(l0).get_pte_array()[idxs[2]] = PageTableEntry::new(
+        PhysPageNum::from(UART_BASE >> 12),
+        PTEFlags::V | PTEFlags::R | PTEFlags::W | PTEFlags::A | PTEFlags::D
+    );
+    // lbu a0, 7(a1): 读取串口的 SCR
+    unsafe{ core::ptr::write(gpa2hpa(code, 0) as *mut u32, 0x0075_c503) };
+    guest.virt_device.uart.scratch = 0x5a;
+    // 只设置 satp 而不建立影子页表，取指时 GVA 按照 GPA 翻译
+    guest.shadow_state_mut().csrs.satp = (8 << 60) | (root >> 12);
+    let mut ctx = TrapContext::app_init_context(0, 0, 0, 0, 0);
+    ctx.x[11] = UART_BASE;
+    ctx.sepc = code;
+    unsafe{ core::arch::asm!("csrw stval, {}", in(reg) UART_BASE + 7) };
+
+    // guest 用户态的访问不被模拟，由 guest 内核处理页错误
+    guest.shadow_state_mut().supervisor = false;
+    assert!(!handle_page_fault(guest, &mut ctx, AccessType::Load).unwrap());
+    assert_eq!(ctx.x[10], 0);
+    assert_eq!(ctx.sepc, code);
+    // guest 内核态的访问被模拟
+    guest.shadow_state_mut().supervisor = true;
+    assert!(handle_page_fault(guest, &mut ctx, AccessType::Load).unwrap());
+    assert_eq!(ctx.x[10], 0x5a);
+    assert_eq!(ctx.sepc, code + 4);
+
+    guest.virt_device.uart.scratch = scratch;
+    *guest.shadow_state_mut() = shadow_state;
+    guest.stats = stats;
+    [root, l1, l0, code].iter().zip(saved.iter()).for_each(|(&gpa, page)| host_page(gpa).get_bytes_array().copy_from_slice(page));
+    hdebug!("mmio permission test passed!");
+}
@@ -0,0 +1,313 @@
+//! Guest OS SBI 调用处理
+//!
+//! 根据 `a7`(EID) 与 `a6`(FID) 分发 guest 的 SBI 调用，hypervisor 作为 guest 的 SBI 实现，
+//! 不会将 guest 的调用直接转发给机器模式的固件。
+
+use riscv::addr::BitField;
+
+use crate::constants::csr::sip::SSIP_BIT;
+use crate::debug::PageDebug;
+use crate::guest::{GuestKernel, GuestState, VCpuState, is_guest_memory};
+use crate::guest::sbi::*;
+use crate::page_table::PageTable;
+use crate::sbi::{ console_getchar, get_mvendorid, get_marchid, get_mimpid };
+use crate::sbi::remote_sfence_vma as host_remote_sfence_vma;
+
+use super::TrapContext;
+use super::GuestResult;
+use super::set_virtual_timer;
+use super::hypercall::handle_hypercall;
+
+/// 处理 guest 内核态的 SBI 调用，未实现的扩展返回 `SBI_ERR_NOT_SUPPORTED`
+pub fn handle_sbi_call<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, ctx: &mut TrapContext) -> GuestResult {
+    let eid = ctx.x[17];
+    let fid = ctx.x[16];
+    match eid {
+        SBI_SET_TIMER..=SBI_SHUTDOWN => handle_legacy_call(guest, ctx, eid),
+        _ => {
+            let ret = match eid {
+                EID_BASE => handle_base(fid, ctx.x[10]),
+                EID_TIME => handle_time(guest, fid, ctx.x[10]),
+                EID_HSM => handle_hsm(guest, fid, ctx.x[10], ctx.x[11], ctx.x[12]),
+                EID_IPI => handle_ipi(guest, fid, ctx.x[10], ctx.x[11]),
+                EID_DBCN => handle_dbcn(guest, fid, ctx.x[10], ctx.x[11], ctx.x[12]),
+                EID_HYPOCAUST => handle_hypercall(guest, fid, ctx.x[10], ctx.x[11]),
+                EID_SRST => handle_srst(guest, fid, ctx.x[10], ctx.x[11]),
+                EID_RFENCE => handle_rfence(guest, fid, ctx.x[10], ctx.x[11], ctx.x[12], ctx.x[13], ctx.x[14])?,
+                _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED)
+            };
+            ctx.x[10] = ret.error as usize;
+            ctx.x[11] = ret.value;
+            Ok(())
+        }
+    }
+}
+
+/// legacy 扩展，返回值只写入 `a0`
+fn handle_legacy_call<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, ctx: &mut TrapContext, eid: usize) -> GuestResult {
+    match eid {
+        SBI_SET_TIMER => set_virtual_timer(guest, ctx.x[10]),
+        SBI_CONSOLE_PUTCHAR => {
+            let c = ctx.x[10];
+            guest.virt_device.console.putchar(c as u8);
+        }
+        SBI_CONSOLE_GETCHAR => {
+            // 等待输入之前输出没有换行的提示符
+            guest.virt_device.console.flush_partial();
+            let c = console_getchar();
+            ctx.x[10] = c;
+        }
+        SBI_CLEAR_IPI => {
+            guest.shadow_state_mut().csrs.sip.set_bit(SSIP_BIT, false);
+            ctx.x[10] = 0;
+        }
+        SBI_SEND_IPI | SBI_REMOTE_FENCE_I | SBI_REMOTE_SFENCE_VMA | SBI_REMOTE_SFENCE_VMA_ASID => {
+            // legacy 扩展的 `a0` 为 hart mask 的虚拟地址，为 0 时表示所有 hart
+            let vcpus = match ctx.x[10] {
+                0 => hart_mask_to_vcpus(guest, 0, usize::MAX),
+                hart_mask_ptr => match guest.translate_guest_vaddr(hart_mask_ptr) {
+                    Some(paddr) => hart_mask_to_vcpus(guest, unsafe{ core::ptr::read(paddr as *const usize) }, 0),
+                    None => Err(SBI_ERR_INVALID_ADDRESS)
+                }
+            };
+            ctx.x[10] = match vcpus {
+                Ok(vcpus) => {
+                    match eid {
+                        SBI_SEND_IPI => send_ipi(guest, vcpus),
+                        SBI_REMOTE_FENCE_I => {},
+                        SBI_REMOTE_SFENCE_VMA => remote_sfence_vma(guest, ctx.x[11], ctx.x[12], None)?,
+                        _ => remote_sfence_vma(guest, ctx.x[11], ctx.x[12], Some(ctx.x[13]))?
+                    }
+                    SBI_SUCCESS as usize
+                }
+                Err(error) => error as usize
+            };
+        }
+        SBI_SHUTDOWN => {
+            guest.stop_request = Some(GuestState::Halted);
+        }
+        _ => unreachable!()
+    }
+    Ok(())
+}
+
+/// 将 hart mask 转换为 vCPU 位图，`hart_mask_base` 为 `usize::MAX` 时表示所有 vCPU
+fn hart_mask_to_vcpus<P: PageTable + PageDebug>(guest: &GuestKernel<P>, hart_mask: usize, hart_mask_base: usize) -> Result<usize, isize> {
+    let count = guest.vcpus.len();
+    if hart_mask_base == usize::MAX {
+        return Ok((1 << count) - 1);
+    }
+    let mut vcpus = 0;
+    for bit in (0..usize::BITS as usize).filter(|&bit| hart_mask.get_bit(bit)) {
+        match hart_mask_base.checked_add(bit) {
+            Some(hart_id) if hart_id < count => { vcpus.set_bit(hart_id, true); }
+            _ => return Err(SBI_ERR_INVALID_PARAM)
+        }
+    }
+    Ok(vcpus)
+}
+
+/// 向 `vcpus` 位图中的所有 vCPU 发送软件中断
+fn send_ipi<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, vcpus: usize) {
+    guest.vcpus.iter_mut()
+        .filter(|vcpu| vcpus.get_bit(vcpu.vcpu_id))
+        .for_each(|vcpu| vcpu.send_ipi());
+}
+
+/// 同步 guest 所有 vCPU 共享的影子页表，并刷新所有物理核的 TLB。
+/// 影子页表的虚拟地址与 guest 虚拟地址相同，但不使用 ASID
+fn remote_sfence_vma<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, start: usize, size: usize, asid: Option<usize>) -> GuestResult {
+    let full = (start == 0 && size == 0) || size == usize::MAX;
+    guest.sfence_vma(if full { None } else { Some((start, size)) }, asid)?;
+    let (start, size) = if full || asid.is_some() { (0, usize::MAX) } else { (start, size) };
+    host_remote_sfence_vma(0, usize::MAX, start, size);
+    Ok(())
+}
+
+/// 扩展是否被 hypervisor 实现
+fn probe_extension(eid: usize) -> bool {
+    match eid {
+        SBI_SET_TIMER | SBI_CONSOLE_PUTCHAR | SBI_CONSOLE_GETCHAR | SBI_SHUTDOWN => true,
+        SBI_CLEAR_IPI | SBI_SEND_IPI | SBI_REMOTE_FENCE_I | SBI_REMOTE_SFENCE_VMA | SBI_REMOTE_SFENCE_VMA_ASID => true,
+        EID_BASE | EID_TIME | EID_HSM | EID_IPI | EID_RFENCE | EID_SRST | EID_DBCN => true,
+        EID_HYPOCAUST => true,
+        _ => false
+    }
+}
+
+/// Base 扩展
+fn handle_base(fid: usize, arg0: usize) -> SbiRet {
+    match fid {
+        BASE_GET_SPEC_VERSION => SbiRet::ok(SBI_SPEC_VERSION),
+        BASE_GET_IMPL_ID => SbiRet::ok(SBI_IMPL_ID),
+        BASE_GET_IMPL_VERSION => SbiRet::ok(SBI_IMPL_VERSION),
+        BASE_PROBE_EXTENSION => SbiRet::ok(probe_extension(arg0) as usize),
+        BASE_GET_MVENDORID => SbiRet::ok(get_mvendorid()),
+        BASE_GET_MARCHID => SbiRet::ok(get_marchid()),
+        BASE_GET_MIMPID => SbiRet::ok(get_mimpid()),
+        _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED)
+    }
+}
+
+/// TIME 扩展
+fn handle_time<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, fid: usize, stime: usize) -> SbiRet {
+    match fid {
+        TIME_SET_TIMER => {
+            set_virtual_timer(guest, stime);
+            SbiRet::ok(0)
+        }
+        _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED)
+    }
+}
+
+/// HSM 扩展，guest 的 hart id 即为 vCPU id
+fn handle_hsm<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> SbiRet {
+    match fid {
+        HSM_HART_START => {
+            let (hart_id, start_addr, opaque) = (arg0, arg1, arg2);
+            if hart_id >= guest.vcpus.len() {
+                return SbiRet::error(SBI_ERR_INVALID_PARAM);
+            }
+            if guest.vcpus[hart_id].state != VCpuState::Stopped {
+                return SbiRet::error(SBI_ERR_ALREADY_AVAILABLE);
+            }
+            // vCPU 启动时没有开启分页，入口地址为 guest 物理地址，必须位于 guest 内存中(不能是跳板页或 Trap Context)
+            if !is_guest_memory(start_addr) {
+                return SbiRet::error(SBI_ERR_INVALID_ADDRESS);
+            }
+            guest.vcpus[hart_id].start(start_addr, opaque);
+            SbiRet::ok(0)
+        }
+        HSM_HART_STOP => {
+            // 停止当前 vCPU，随后由调度器切换到其他 vCPU
+            guest.vcpu_mut().stop();
+            SbiRet::ok(0)
+        }
+        HSM_HART_GET_STATUS => {
+            let hart_id = arg0;
+            match guest.vcpus.get(hart_id) {
+                Some(vcpu) => SbiRet::ok(match vcpu.state {
+                    VCpuState::Stopped => HSM_STATUS_STOPPED,
+                    VCpuState::Suspended => HSM_STATUS_SUSPENDED,
+                    _ if vcpu.entry.is_some() && vcpu.resuming => HSM_STATUS_RESUME_PENDING,
+                    _ if vcpu.entry.is_some() => HSM_STATUS_START_PENDING,
+                    _ => HSM_STATUS_STARTED
+                }),
+                None => SbiRet::error(SBI_ERR_INVALID_PARAM)
+            }
+        }
+        HSM_HART_SUSPEND => {
+            let (suspend_type, resume_addr, opaque) = (arg0, arg1, arg2);
+            match suspend_type {
+                HSM_SUSPEND_RETENTIVE => {}
+                HSM_SUSPEND_NON_RETENTIVE => {
+                    if !is_guest_memory(resume_addr) {
+                        return SbiRet::error(SBI_ERR_INVALID_ADDRESS);
+                    }
+                    // 唤醒后从 `resume_addr` 开始运行
+                    guest.vcpu_mut().suspend_non_retentive(resume_addr, opaque);
+                }
+                0x1..=0x0FFF_FFFF | 0x8000_0001..=0x8FFF_FFFF => return SbiRet::error(SBI_ERR_INVALID_PARAM),
+                _ => return SbiRet::error(SBI_ERR_NOT_SUPPORTED)
+            }
+            // 挂起当前 vCPU，直到有中断等待处理时被唤醒
+            guest.vcpu_mut().state = VCpuState::Suspended;
+            SbiRet::ok(0)
+        }
+        _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED)
+    }
+}
+
+/// IPI 扩展
+fn handle_ipi<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, fid: usize, hart_mask: usize, hart_mask_base: usize) -> SbiRet {
+    match fid {
+        IPI_SEND_IPI => match hart_mask_to_vcpus(guest, hart_mask, hart_mask_base) {
+            Ok(vcpus) => {
+                send_ipi(guest, vcpus);
+                SbiRet::ok(0)
+            }
+            Err(error) => SbiRet::error(error)
+        }
+        _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED)
+    }
+}
+
+/// RFENCE 扩展，不支持 hypervisor 扩展相关的 fence
+fn handle_rfence<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, fid: usize, hart_mask: usize, hart_mask_base: usize, start: usize, size: usize, asid: usize) -> GuestResult<SbiRet> {
+    if let Err(error) = hart_mask_to_vcpus(guest, hart_mask, hart_mask_base) {
+        return Ok(SbiRet::error(error));
+    }
+    match fid {
+        // guest 的指令缓存在每次陷入返回时都会刷新
+        RFENCE_REMOTE_FENCE_I => {},
+        RFENCE_REMOTE_SFENCE_VMA => remote_sfence_vma(guest, start, size, None)?,
+        RFENCE_REMOTE_SFENCE_VMA_ASID => remote_sfence_vma(guest, start, size, Some(asid))?,
+        _ => return Ok(SbiRet::error(SBI_ERR_NOT_SUPPORTED))
+    }
+    Ok(SbiRet::ok(0))
+}
+
+/// SRST 扩展，只会关闭或重启发起调用的 guest
+fn handle_srst<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, fid: usize, reset_type: usize, reset_reason: usize) -> SbiRet {
+    if fid != SRST_SYSTEM_RESET {
+        return SbiRet::error(SBI_ERR_NOT_SUPPORTED);
+    }
+    match reset_reason {
+        SRST_REASON_NONE | SRST_REASON_SYSTEM_FAILURE => {}
+        0x2..=0xEFFF_FFFF => return SbiRet::error(SBI_ERR_INVALID_PARAM),
+        _ => {}
+    }
+    guest.virt_device.console.flush_partial();
+    match reset_type {
+        SRST_TYPE_SHUTDOWN => {
+            hdebug!("guest kernel {} shutdown, reason: {}", guest.guest_id, reset_reason);
+            guest.stop_request = Some(GuestState::Halted);
+        }
+        SRST_TYPE_COLD_REBOOT | SRST_TYPE_WARM_REBOOT => {
+            hdebug!("guest kernel {} reboot, reason: {}", guest.guest_id, reset_reason);
+            // 所有 vCPU 让出处理器之后由调度器重置 guest
+            guest.state = GuestState::Rebooting;
+        }
+        0x3..=0xEFFF_FFFF => return SbiRet::error(SBI_ERR_INVALID_PARAM),
+        _ => return SbiRet::error(SBI_ERR_NOT_SUPPORTED)
+    }
+    SbiRet::ok(0)
+}
+
+/// DBCN 扩展，缓冲区使用 guest 物理地址
+fn handle_dbcn<P: PageTable + PageDebug>(guest: &mut GuestKernel<P>, fid: usize, num_bytes: usize, base_addr_lo: usize, base_addr_hi: usize) -> SbiRet {
+    match fid {
+        DBCN_CONSOLE_WRITE | DBCN_CONSOLE_READ => {
+            if base_addr_hi != 0 {
+                return SbiRet::error(SBI_ERR_INVALID_PARAM);
+            }
+            let slices = match guest.guest_paddr_slices(base_addr_lo, num_bytes) {
+                Some(slices) => slices,
+                None => return SbiRet::error(SBI_ERR_INVALID_PARAM)
+            };
+            if fid == DBCN_CONSOLE_WRITE {
+                slices.iter().for_each(|slice| guest.virt_device.console.write(slice));
+                SbiRet::ok(num_bytes)
+            }else{
+                guest.virt_device.console.flush_partial();
+                // 读取所有已经到达的字符，没有输入时立即返回
+                let mut count = 0;
+                for slice in slices {
+                    for byte in slice.iter_mut() {
+                        match console_getchar() {
+                            c if c > u8::MAX as usize => return SbiRet::ok(count),
+                            c => *byte = c as u8
+                        }
+                        count += 1;
+                    }
+                }
+                SbiRet::ok(count)
+            }
+        }
+        DBCN_CONSOLE_WRITE_BYTE => {
+            guest.virt_device.console.putchar(num_bytes as u8);
+            SbiRet::ok(0)
+        }
+        _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED)
+    }
+}
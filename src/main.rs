@@ -35,10 +35,9 @@ mod hypervisor;
 
 
 
-use crate::constants::layout::PAGE_SIZE;
-use crate::guest::GuestKernel;
+use crate::constants::layout::{PAGE_SIZE, MAX_HARTS};
+use crate::guest::GuestConfig;
 use crate::hypervisor::HYPOCAUST;
-use crate::mm::MemorySet;
 
 // use fdt::Fdt;
 
@@ -53,8 +52,8 @@ static GUEST_KERNEL: [u8;include_bytes!("../guest_kernel").len()] =
  const BOOT_STACK_SIZE: usize = 16 * PAGE_SIZE;
 
 #[link_section = ".bss.stack"]
-/// hypocaust boot stack
-static BOOT_STACK: [u8; BOOT_STACK_SIZE] = [0u8; BOOT_STACK_SIZE];
+/// hypocaust boot stack, each hart owns `BOOT_STACK_SIZE` bytes
+static BOOT_STACK: [u8; BOOT_STACK_SIZE * MAX_HARTS] = [0u8; BOOT_STACK_SIZE * MAX_HARTS];
 
 #[link_section = ".text.entry"]
 #[export_name = "_start"]
@@ -62,6 +61,8 @@ static BOOT_STACK: [u8; BOOT_STACK_SIZE] = [0u8; BOOT_STACK_SIZE];
 /// hypocaust entrypoint
 pub unsafe extern "C" fn start() -> ! {
     core::arch::asm!(
+        // keep hart id in tp
+        "mv tp, a0",
         // prepare stack
         "la sp, {boot_stack}",
         "li t2, {boot_stack_size}",
@@ -98,27 +99,50 @@ pub fn hentry(hart_id: usize, device_tree_blob: usize) -> ! {
         // 初始化堆及帧分配器
         hypervisor::hyp_alloc::heap_init();
         hypervisor::initialize_vmm(meta);
-        let mut hypervisor = HYPOCAUST.lock();
-        let hypervisor = {&mut *hypervisor}.as_mut().unwrap();
-        let guest_kernel_memory = MemorySet::new_guest_kernel(&GUEST_KERNEL);
         // 初始化虚拟内存
-        mm::vm_init(&guest_kernel_memory);
+        mm::vm_init();
         hypervisor::trap::init();
         // 测试重映射
         mm::remap_test();
+        // 创建 guest kernel
+        let hart_count = {
+            let mut inner = HYPOCAUST.lock();
+            let hypervisor = inner.as_mut().unwrap();
+            hypervisor.create_guest(&GUEST_KERNEL, GuestConfig::default()).unwrap();
+            hypervisor.meta.hart_count
+        };
         // 测试 guest kernel 内存映射
         mm::guest_kernel_test();
-        // 开启时钟中断
-        hypervisor::trap::enable_timer_interrupt();
-        timer::set_default_next_trigger();
-        // 创建用户态的 guest kernel 内存空间
-        let user_guest_kernel_memory = MemorySet::create_user_guest_kernel(&guest_kernel_memory);
-        let guest = GuestKernel::new(user_guest_kernel_memory, 0);
-        // 开始运行 guest kernel
-        hypervisor.add_guest(guest);
-        hypervisor.run_guest(0)
+        // 测试 guest 生命周期
+        hypervisor::guest_lifecycle_test(&GUEST_KERNEL);
+        // 测试共享页注册
+        guest::shared_page_test();
+        // 测试 guest ecall 分发
+        hypervisor::trap::ecall_test();
+        // 测试虚拟陷入注入
+        hypervisor::trap::trap_delivery_test();
+        // 测试 guest 用户态访问内核映射的设备
+        hypervisor::trap::mmio_permission_test();
+        // 测试虚拟 PLIC
+        device_emu::plic_test();
+        // 测试虚拟串口
+        device_emu::uart_test();
+        // 启动其他核
+        hypervisor::hart::start_secondary_harts(hart_count, device_tree_blob);
     }else{
-        unreachable!()
+        hdebug!("hart {} started", hart_id);
+        // 从核使用主核建立的 hypervisor 地址空间
+        mm::vm_init();
+        hypervisor::trap::init();
     }
+    // 开启时钟中断
+    hypervisor::trap::enable_timer_interrupt();
+    timer::set_default_next_trigger();
+    // 开启外部中断
+    hypervisor::trap::enable_external_interrupt();
+    // 开启软件中断，其他核通过 IPI 通知新的中断
+    hypervisor::trap::enable_software_interrupt();
+    // 开始运行 guest kernel
+    hypervisor::run_guests()
 }
 
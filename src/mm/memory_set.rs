@@ -2,13 +2,13 @@
 
 use crate::hypervisor::hyp_alloc::{FrameTracker, frame_alloc};
 use crate::hypervisor::HYPERVISOR_MEMORY;
+use crate::device_emu::is_device_access;
 use crate::page_table::{PTEFlags, PageTable, PageTableEntry};
 use crate::page_table::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
 use crate::page_table::{StepByOne, VPNRange, PPNRange};
 use crate::constants::layout::{ 
-    PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT,  GUEST_KERNEL_PHY_START_1, 
-    GUEST_KERNEL_VIRT_START, MEMORY_END, MMIO, 
-    GUEST_KERNEL_VIRT_END, GUEST_KERNEL_PHY_END_1, SPT_PA_START_1, SPT_PA_END_1
+    PAGE_SIZE, TRAMPOLINE, GUEST_KERNEL_VIRT_START, MEMORY_END, MMIO, 
+    GUEST_KERNEL_VIRT_END, MAX_VCPUS, guest_kernel_phy_position, spt_position, trap_context_position
 };
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
@@ -65,6 +65,19 @@ impl<P> MemorySet<P> where P: PageTable {
         );
     }
 
+    /// 解除以 `start_vpn` 开始的内存区域的映射，并释放其物理页帧
+    pub fn remove_area_with_start_vpn(&mut self, start_vpn: VirtPageNum) {
+        if let Some((idx, area)) = self
+            .areas
+            .iter_mut()
+            .enumerate()
+            .find(|(_, area)| area.vpn_range.get_start() == start_vpn)
+        {
+            area.unmap(&mut self.page_table);
+            self.areas.remove(idx);
+        }
+    }
+
     /// 将内存区域 push 到页表中，并映射内存区域
     fn push(&mut self, mut map_area: MapArea<P>, data: Option<&[u8]>) {
         map_area.map(&mut self.page_table);
@@ -146,36 +159,28 @@ impl<P> MemorySet<P> where P: PageTable {
             None,
         );
 
-        // 影子页表映射区域
-        memory_set.push(
-            MapArea::new(
-                VirtAddr::from(SPT_PA_START_1),
-                VirtAddr::from(SPT_PA_END_1),
-                Some(PhysAddr::from(SPT_PA_START_1)),
-                Some(PhysAddr::from(SPT_PA_END_1)),
-                MapType::Linear,
-                MapPermission::R | MapPermission::W
-            ),
-            None
-        );
-
         for pair in MMIO {
-            memory_set.push(
-                MapArea::new(
-                    (*pair).0.into(),
-                    ((*pair).0 + (*pair).1).into(),
-                    Some((*pair).0.into()),
-                    Some(((*pair).0 + (*pair).1).into()),
-                    MapType::Linear,
-                    MapPermission::R | MapPermission::W,
-                ),
-                None,
-            );
+            memory_set.map_mmio((*pair).0, (*pair).1);
         }
 
         memory_set
     }
 
+    /// 为 hypervisor 线性映射设备寄存器区域
+    pub fn map_mmio(&mut self, base: usize, size: usize) {
+        self.push(
+            MapArea::new(
+                base.into(),
+                (base + size).into(),
+                Some(base.into()),
+                Some((base + size).into()),
+                MapType::Linear,
+                MapPermission::R | MapPermission::W,
+            ),
+            None,
+        );
+    }
+
     /// 创建用户态的 Guest Kernel 内存空间
     pub fn create_user_guest_kernel(guest_kernel_memory: &Self) -> Self {
         let mut memory_set = Self::new_bare();
@@ -190,10 +195,10 @@ impl<P> MemorySet<P> where P: PageTable {
         }
         // 创建跳板页映射
         memory_set.map_trampoline();
-        // 映射 Trap Context
+        // 映射所有 vCPU 的 Trap Context
         memory_set.push(
             MapArea::new(
-                TRAP_CONTEXT.into(),
+                trap_context_position(MAX_VCPUS - 1).into(),
                 TRAMPOLINE.into(),
                 None,
                 None,
@@ -202,8 +207,8 @@ impl<P> MemorySet<P> where P: PageTable {
             ),
             None,
         );
-        
-        for pair in MMIO {
+        // 只映射直通的设备，模拟设备不映射，guest 访问时触发页错误并由 hypervisor 模拟
+        for pair in MMIO.iter().filter(|pair| is_device_access(pair.0)) {
             memory_set.push(
                 MapArea::new(
                     (*pair).0.into(),
@@ -219,17 +224,19 @@ impl<P> MemorySet<P> where P: PageTable {
         memory_set
     }
 
-    pub fn new_guest_kernel(guest_kernel_data: &[u8]) -> Self {
+    pub fn new_guest_kernel(guest_kernel_data: &[u8], guest_id: usize) -> Self {
         let mut memory_set = Self::new_bare();
         let elf = xmas_elf::ElfFile::new(guest_kernel_data).unwrap();
         let elf_header = elf.header;
         let magic = elf_header.pt1.magic;
         assert_eq!(magic, [0x7f, 0x45, 0x4c, 0x46], "invalid elf!");
         let ph_count = elf_header.pt2.ph_count();
-        // 物理内存,从 0x8800_0000 开始
+        // 物理内存,从 guest 对应的物理内存段开始(guest 0 为 0x8800_0000)
         // 虚拟内存,从 0x8000_0000 开始
-        let mut paddr = GUEST_KERNEL_PHY_START_1 as *mut u8;
-        let mut last_paddr = GUEST_KERNEL_PHY_START_1 as *mut u8;
+        let (guest_phy_start, guest_phy_end) = guest_kernel_phy_position(guest_id);
+        load_guest_kernel_image(guest_kernel_data, guest_id);
+        let mut paddr = guest_phy_start as *mut u8;
+        let mut last_paddr = guest_phy_start as *mut u8;
         for i in 0..ph_count {
             let ph = elf.program_header(i).unwrap();
             if ph.get_type().unwrap() == xmas_elf::program::Type::Load {
@@ -246,9 +253,7 @@ impl<P> MemorySet<P> where P: PageTable {
                 if ph_flags.is_execute() {
                     map_perm |= MapPermission::X;
                 }
-                // 将内存拷贝到对应的物理内存上
                 unsafe{
-                    core::ptr::copy(guest_kernel_data.as_ptr().add(ph.offset() as usize), paddr, ph.file_size() as usize);
                     let page_align_size = ((ph.mem_size() as usize + PAGE_SIZE - 1) >> 12) << 12;
                     paddr = paddr.add(page_align_size);
                 }
@@ -266,13 +271,13 @@ impl<P> MemorySet<P> where P: PageTable {
             }
             
         }
-        let offset = paddr as usize - GUEST_KERNEL_PHY_START_1;
+        let offset = paddr as usize - guest_phy_start;
         // 映射其他物理内存
         memory_set.push(MapArea::new(
                 VirtAddr(offset + GUEST_KERNEL_VIRT_START), 
                 VirtAddr(GUEST_KERNEL_VIRT_END), 
                 Some(PhysAddr(paddr as usize)), 
-                Some(PhysAddr(GUEST_KERNEL_PHY_END_1)), 
+                Some(PhysAddr(guest_phy_end)), 
                 MapType::Linear, 
                 MapPermission::R | MapPermission::W
             ),
@@ -282,25 +287,45 @@ impl<P> MemorySet<P> where P: PageTable {
         memory_set
     }
 
-    /// 加载客户操作系统
-    pub fn hyper_load_guest_kernel(&mut self, guest_kernel_memory: &Self) {
-        for area in guest_kernel_memory.areas.iter() {
-            // 修改虚拟地址与物理地址相同
-            let ppn_range = area.ppn_range.unwrap();
-            let start_pa: PhysAddr = ppn_range.get_start().into();
-            let end_pa: PhysAddr = ppn_range.get_end().into();
-            let start_va: usize = start_pa.into();
-            let end_va: usize= end_pa.into();
-            let new_area = MapArea::new(
-                start_va.into(), 
-                end_va.into(), 
-                Some(start_pa),
-                Some(end_pa), 
-                area.map_type, 
-                area.map_perm
-            );
-            self.push(new_area, None);
-        }
+    /// 为 hypervisor 线性映射 guest 的物理内存段与影子页表区域，
+    /// 需要在加载 guest kernel 镜像之前调用
+    pub fn map_guest_segment(&mut self, guest_id: usize) {
+        let (guest_phy_start, guest_phy_end) = guest_kernel_phy_position(guest_id);
+        let (spt_start, spt_end) = spt_position(guest_id);
+        // guest 物理内存段
+        self.push(
+            MapArea::new(
+                VirtAddr::from(guest_phy_start),
+                VirtAddr::from(guest_phy_end),
+                Some(PhysAddr::from(guest_phy_start)),
+                Some(PhysAddr::from(guest_phy_end)),
+                MapType::Linear,
+                MapPermission::R | MapPermission::W | MapPermission::X
+            ),
+            None
+        );
+        // 影子页表映射区域
+        self.push(
+            MapArea::new(
+                VirtAddr::from(spt_start),
+                VirtAddr::from(spt_end),
+                Some(PhysAddr::from(spt_start)),
+                Some(PhysAddr::from(spt_end)),
+                MapType::Linear,
+                MapPermission::R | MapPermission::W
+            ),
+            None
+        );
+        unsafe{ asm!("sfence.vma") };
+    }
+
+    /// 解除 `map_guest_segment` 建立的映射
+    pub fn unmap_guest_segment(&mut self, guest_id: usize) {
+        let (guest_phy_start, _) = guest_kernel_phy_position(guest_id);
+        let (spt_start, _) = spt_position(guest_id);
+        self.remove_area_with_start_vpn(VirtAddr::from(guest_phy_start).floor());
+        self.remove_area_with_start_vpn(VirtAddr::from(spt_start).floor());
+        unsafe{ asm!("sfence.vma") };
     }
 
 
@@ -440,6 +465,25 @@ impl<P> MapArea<P> where P: PageTable {
 
 }
 
+/// 将 guest kernel 镜像的各个段依次拷贝到 guest 对应的物理内存段，并将剩余部分(.bss)清零，
+/// 段在物理内存中按页对齐连续存放，与 `MemorySet::new_guest_kernel` 建立的映射一致
+pub fn load_guest_kernel_image(guest_kernel_data: &[u8], guest_id: usize) {
+    let elf = xmas_elf::ElfFile::new(guest_kernel_data).unwrap();
+    let (guest_phy_start, _) = guest_kernel_phy_position(guest_id);
+    let mut paddr = guest_phy_start as *mut u8;
+    for i in 0..elf.header.pt2.ph_count() {
+        let ph = elf.program_header(i).unwrap();
+        if ph.get_type().unwrap() == xmas_elf::program::Type::Load {
+            unsafe{
+                core::ptr::copy(guest_kernel_data.as_ptr().add(ph.offset() as usize), paddr, ph.file_size() as usize);
+                core::ptr::write_bytes(paddr.add(ph.file_size() as usize), 0, (ph.mem_size() - ph.file_size()) as usize);
+                let page_align_size = ((ph.mem_size() as usize + PAGE_SIZE - 1) >> 12) << 12;
+                paddr = paddr.add(page_align_size);
+            }
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 /// map type for memory set: identical or framed
 pub enum MapType {
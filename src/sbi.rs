@@ -6,6 +6,9 @@ use core::arch::asm;
 const SBI_CONSOLE_PUTCHAR: usize = 1;
 const SBI_CONSOLE_GETCHAR: usize = 2;
 
+const EID_RFENCE: usize = 0x5246_4E43;
+const RFENCE_REMOTE_SFENCE_VMA: usize = 1;
+
 
 #[inline(always)]
 /// general sbi call
@@ -24,6 +27,24 @@ fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
     ret
 }
 
+#[inline(always)]
+/// sbi call with extension id and function id, return (error, value)
+fn sbi_call_ext(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize) -> (usize, usize) {
+    let (error, value);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("x10") arg0 => error,
+            inlateout("x11") arg1 => value,
+            in("x12") arg2,
+            in("x13") arg3,
+            in("x16") fid,
+            in("x17") eid,
+        );
+    }
+    (error, value)
+}
+
 /// use sbi call to putchar in console (qemu uart handler)
 pub fn console_putchar(c: usize) {
     sbi_call(SBI_CONSOLE_PUTCHAR, c, 0, 0);
@@ -38,8 +59,44 @@ pub fn set_timer(stime: usize) {
     sbi_rt::set_timer(stime as u64);
 }
 
+/// use sbi call to start a hart at `start_addr` with `a1 = opaque`, return sbi error code
+pub fn hart_start(hart_id: usize, start_addr: usize, opaque: usize) -> usize {
+    sbi_rt::hart_start(hart_id, start_addr, opaque).error
+}
+
+/// use sbi call to send a supervisor software interrupt to `hart_id`, return sbi error code
+pub fn send_ipi(hart_id: usize) -> usize {
+    sbi_rt::send_ipi(1, hart_id).error
+}
+
+/// use sbi call to flush TLB of the harts in `hart_mask`, return sbi error code
+pub fn remote_sfence_vma(hart_mask: usize, hart_mask_base: usize, start_addr: usize, size: usize) -> usize {
+    sbi_call_ext(EID_RFENCE, RFENCE_REMOTE_SFENCE_VMA, hart_mask, hart_mask_base, start_addr, size).0
+}
+
+/// use sbi call to power off the machine normally
+pub fn poweroff() -> ! {
+    sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::NoReason);
+    unreachable!()
+}
+
 /// use sbi call to shutdown the kernel
 pub fn shutdown() -> ! {
     sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::SystemFailure);
     unreachable!()
 }
+
+/// use sbi call to get machine vendor id of the host
+pub fn get_mvendorid() -> usize {
+    sbi_rt::get_mvendorid()
+}
+
+/// use sbi call to get machine architecture id of the host
+pub fn get_marchid() -> usize {
+    sbi_rt::get_marchid()
+}
+
+/// use sbi call to get machine implementation id of the host
+pub fn get_mimpid() -> usize {
+    sbi_rt::get_mimpid()
+}
@@ -1,31 +1,32 @@
-//! Uniprocessor interior mutability primitives
+//! Interior mutability primitives shared by all harts
 
-use core::cell::{RefCell, RefMut};
+use spin::{Mutex, MutexGuard};
 
 /// Wrap a static data structure inside it so that we are
 /// able to access it without any `unsafe`.
 ///
-/// We should only use it in uniprocessor.
+/// It was designed for uniprocessor, now it is backed by a spin lock
+/// so that secondary harts can share it as well.
 ///
 /// In order to get mutable reference of inner data, call
 /// `exclusive_access`.
 pub struct UPSafeCell<T> {
     /// inner data
-    inner: RefCell<T>,
+    inner: Mutex<T>,
 }
 
 unsafe impl<T> Sync for UPSafeCell<T> {}
 
 impl<T> UPSafeCell<T> {
-    /// User is responsible to guarantee that inner struct is only used in
-    /// uniprocessor.
+    /// User is responsible to guarantee that inner struct is never
+    /// accessed recursively on the same hart, which would dead lock.
     pub unsafe fn new(value: T) -> Self {
         Self {
-            inner: RefCell::new(value),
+            inner: Mutex::new(value),
         }
     }
-    /// Exclusive access inner data in UPSafeCell. Panic if the data has been borrowed.
-    pub fn exclusive_access(&self) -> RefMut<'_, T> {
-        self.inner.borrow_mut()
+    /// Exclusive access inner data in UPSafeCell. Spin if the data is accessed by another hart.
+    pub fn exclusive_access(&self) -> MutexGuard<'_, T> {
+        self.inner.lock()
     }
 }
@@ -1,12 +1,23 @@
 //! RISC-V timer-related functionality
+//!
+//! 每个核只有一个物理时钟，hypervisor 的调度时钟与当前 guest 的虚拟时钟复用该物理时钟：
+//! 物理时钟总是被设置为两者中较早的一个。
 
-use crate::constants::layout::CLOCK_FREQ;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::constants::layout::{CLOCK_FREQ, MAX_HARTS};
+use crate::hypervisor::hart_id;
 use riscv::register::time;
 use crate::sbi::set_timer;
 
 const TICKS_PER_SEC: usize = 100;
 const MSEC_PER_SEC: usize = 1000;
 
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_TICK: AtomicUsize = AtomicUsize::new(usize::MAX);
+/// 每个核上 hypervisor 调度时钟的下一次触发时间
+static NEXT_TICK: [AtomicUsize; MAX_HARTS] = [NO_TICK; MAX_HARTS];
+
 pub fn get_default_timer() -> usize {
     CLOCK_FREQ / TICKS_PER_SEC
 }
@@ -20,11 +31,26 @@ pub fn get_time_ms() -> usize {
     time::read() / (CLOCK_FREQ / MSEC_PER_SEC)
 }
 
-pub fn set_next_trigger(stimer: usize) {
-    set_timer(stimer);
+/// 设置物理时钟为调度时钟与 guest 虚拟时钟中较早的一个
+pub fn set_next_trigger(guest_deadline: usize) {
+    let tick = NEXT_TICK[hart_id()].load(Ordering::Relaxed);
+    set_timer(tick.min(guest_deadline));
 } 
 
 /// set the next timer interrupt
 pub fn set_default_next_trigger() {
-    set_timer(get_time() + CLOCK_FREQ / TICKS_PER_SEC);
+    let tick = get_time() + get_default_timer();
+    NEXT_TICK[hart_id()].store(tick, Ordering::Relaxed);
+    set_timer(tick);
+}
+
+/// 调度时钟是否到期，到期时重新设置下一次调度时间(不会设置物理时钟)
+pub fn tick_expired(now: usize) -> bool {
+    let next_tick = &NEXT_TICK[hart_id()];
+    if now >= next_tick.load(Ordering::Relaxed) {
+        next_tick.store(now + get_default_timer(), Ordering::Relaxed);
+        true
+    }else{
+        false
+    }
 }